@@ -0,0 +1,131 @@
+use miniz_oxide::deflate::core::{compress, CompressorOxide, TDEFLFlush, TDEFLStatus};
+use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::DataFormat;
+use spin::Mutex;
+
+use super::{Compressor, CompressorId};
+
+/// DEFLATE, either raw or zlib-wrapped, via the pure-Rust `miniz_oxide`.
+///
+/// The compressor and decompressor state is allocated once by `preallocate`
+/// and reset between calls.
+pub struct Deflate {
+    level: u8,
+    zlib: bool,
+    state: Mutex<Option<(Box<CompressorOxide>, Box<DecompressorOxide>)>>,
+}
+
+pub static DEFLATE: Deflate = Deflate::raw(Deflate::DEFAULT_LEVEL);
+pub static DEFLATE_STRONG: Deflate = Deflate::raw(Deflate::MAX_LEVEL);
+pub static ZLIB: Deflate = Deflate::zlib(Deflate::DEFAULT_LEVEL);
+
+impl Deflate {
+    pub const RAW_ID: CompressorId = 4;
+    pub const ZLIB_ID: CompressorId = 5;
+    pub const DEFAULT_LEVEL: u8 = 6;
+    pub const MAX_LEVEL: u8 = 9;
+
+    /// Raw DEFLATE streams, with no header or checksum
+    pub const fn raw(level: u8) -> Self {
+        Self {
+            level,
+            zlib: false,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// DEFLATE wrapped in a zlib header and Adler-32 trailer
+    pub const fn zlib(level: u8) -> Self {
+        Self {
+            level,
+            zlib: true,
+            state: Mutex::new(None),
+        }
+    }
+
+    fn data_format(&self) -> DataFormat {
+        if self.zlib {
+            DataFormat::Zlib
+        } else {
+            DataFormat::Raw
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut CompressorOxide, &mut DecompressorOxide) -> T) -> T {
+        let mut state = self.state.lock();
+        if state.is_none() {
+            tracing::warn!("{} level {} was not preallocated, allocating state now", self.name(), self.level);
+        }
+        let (compressor, decompressor) = state.get_or_insert_with(|| self.allocate_state());
+        f(compressor, decompressor)
+    }
+
+    fn allocate_state(&self) -> (Box<CompressorOxide>, Box<DecompressorOxide>) {
+        let mut compressor = Box::<CompressorOxide>::default();
+        compressor.set_format_and_level(self.data_format(), self.level);
+        (compressor, Box::default())
+    }
+}
+
+impl Compressor for Deflate {
+    fn name(&self) -> &str {
+        if self.zlib {
+            "zlib"
+        } else {
+            "deflate"
+        }
+    }
+
+    fn id(&self) -> CompressorId {
+        if self.zlib {
+            Self::ZLIB_ID
+        } else {
+            Self::RAW_ID
+        }
+    }
+
+    fn level(&self) -> Option<i32> {
+        Some(self.level as i32)
+    }
+
+    fn preallocate(&self) {
+        let mut state = self.state.lock();
+        if state.is_none() {
+            *state = Some(self.allocate_state());
+        }
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        // Same bound as zlib's `compressBound`, plus room for the zlib header and trailer
+        input_len + (input_len >> 12) + (input_len >> 14) + (input_len >> 25) + 13 + 6
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.with_state(|compressor, _| {
+            compressor.reset();
+            let (status, consumed, written) = compress(compressor, input, output, TDEFLFlush::Finish);
+            if status != TDEFLStatus::Done || consumed != input.len() {
+                tracing::error!("Could not compress {} data: {status:?}", self.name());
+                return None;
+            }
+            Some(written)
+        })
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        let mut flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        if self.zlib {
+            flags |= inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER;
+        }
+        self.with_state(|_, decompressor| {
+            decompressor.init();
+            let (status, _, written) = decompress(decompressor, input, output, 0, flags);
+            if status != TINFLStatus::Done {
+                tracing::error!("Could not decompress {} data: {status:?}", self.name());
+                return None;
+            }
+            Some(written)
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use spin::Mutex;
+use zstd_safe::{CCtx, CDict, DCtx, DDict};
+
+use super::{Compressor, CompressorId, Zstd};
+
+/// The largest dictionary `ZstdDict::train` builds.
+pub const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+
+/// A trained dictionary, digested for compression and decompression.
+struct Dictionary {
+    id: u32,
+    size: usize,
+    cdict: CDict<'static>,
+    ddict: DDict<'static>,
+}
+
+struct DictState {
+    contexts: Option<(CCtx<'static>, DCtx<'static>)>,
+    dictionary: Option<Dictionary>,
+}
+
+/// Zstandard against a dictionary trained on the application's own heap.
+///
+/// Small objects have too little history of their own for the codec to find
+/// matches in; a dictionary trained from sample frames gives every frame the same
+/// shared history up front. Until `train` succeeds this compresses like `Zstd` at
+/// the same level, and clamps its level the same way. Frames record the ID of the dictionary they were compressed
+/// with, so frames compressed before training still decompress afterwards.
+///
+/// A dictionary is trained once and then kept: frames compressed against it can
+/// only be decompressed with it.
+pub struct ZstdDict {
+    level: i32,
+    state: Mutex<DictState>,
+}
+
+pub static ZSTD_DICT: ZstdDict = ZstdDict::new(ZstdDict::DEFAULT_LEVEL);
+
+impl ZstdDict {
+    pub const ID: CompressorId = 7;
+    pub const DEFAULT_LEVEL: i32 = zstd_safe::CLEVEL_DEFAULT;
+
+    pub const fn new(level: i32) -> Self {
+        Self {
+            level: Zstd::clamp_level(level),
+            state: Mutex::new(DictState {
+                contexts: None,
+                dictionary: None,
+            }),
+        }
+    }
+
+    /// Train the dictionary from `samples`, the concatenation of samples of the
+    /// given `sizes`. Returns the dictionary's ID, or `None` if training failed,
+    /// e.g. because there weren't enough samples, or a dictionary was already trained.
+    pub fn train(&self, samples: &[u8], sizes: &[usize]) -> Option<u32> {
+        let mut state = self.state.lock();
+        if state.dictionary.is_some() {
+            tracing::warn!("Zstd dictionary is already trained, frames compressed against it depend on it");
+            return None;
+        }
+        let mut buffer = std::vec::Vec::with_capacity(MAX_DICTIONARY_SIZE);
+        if let Err(e) = zstd_safe::train_from_buffer(&mut buffer, samples, sizes) {
+            tracing::error!("Could not train a Zstd dictionary from {} samples: {}", sizes.len(), zstd_safe::get_error_name(e));
+            return None;
+        }
+        let Some(id) = zstd_safe::get_dict_id_from_dict(&buffer) else {
+            tracing::error!("Trained Zstd dictionary has no ID");
+            return None;
+        };
+        let (Some(cdict), Some(ddict)) = (CDict::try_create(&buffer, self.level), DDict::try_create(&buffer)) else {
+            tracing::error!("Could not allocate Zstd dictionary {}", id);
+            return None;
+        };
+        let dictionary = Dictionary {
+            id: id.get(),
+            size: buffer.len(),
+            cdict,
+            ddict,
+        };
+        if let Some((cctx, dctx)) = state.contexts.as_mut() {
+            Self::warm_up(cctx, dctx, &dictionary);
+        }
+        state.dictionary = Some(dictionary);
+        Some(id.get())
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.state.lock().dictionary.is_some()
+    }
+
+    /// The trained dictionary's ID and size in bytes.
+    pub fn dictionary(&self) -> Option<(u32, usize)> {
+        self.state.lock().dictionary.as_ref().map(|dictionary| (dictionary.id, dictionary.size))
+    }
+
+    /// Round-trip a frame against `dictionary`, so the contexts grow whatever
+    /// workspace its parameters need now rather than on the first real frame.
+    fn warm_up(cctx: &mut CCtx<'static>, dctx: &mut DCtx<'static>, dictionary: &Dictionary) {
+        let frame = Zstd::warm_up_frame();
+        let mut compressed = std::vec::Vec::with_capacity(zstd_safe::compress_bound(frame.len()));
+        let mut decompressed = std::vec::Vec::with_capacity(frame.len());
+        let round_trip = cctx.compress_using_cdict(&mut compressed, &frame, &dictionary.cdict)
+            .and_then(|_| dctx.decompress_using_ddict(&mut decompressed, &compressed, &dictionary.ddict));
+        if let Err(e) = round_trip {
+            tracing::error!("Could not warm up Zstd dictionary {}: {}", dictionary.id, zstd_safe::get_error_name(e));
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut CCtx<'static>, &mut DCtx<'static>, Option<&Dictionary>) -> T) -> Option<T> {
+        let mut state = self.state.lock();
+        let DictState { contexts, dictionary } = &mut *state;
+        if contexts.is_none() {
+            tracing::warn!("Zstd dictionary codec was not preallocated, allocating contexts now");
+            *contexts = Some((CCtx::try_create()?, DCtx::try_create()?));
+        }
+        let (cctx, dctx) = contexts.as_mut()?;
+        Some(f(cctx, dctx, dictionary.as_ref()))
+    }
+}
+
+impl Compressor for ZstdDict {
+    fn name(&self) -> &str {
+        "zstd-dict"
+    }
+
+    fn id(&self) -> CompressorId {
+        Self::ID
+    }
+
+    fn level(&self) -> Option<i32> {
+        Some(self.level)
+    }
+
+    fn preallocate(&self) {
+        let mut state = self.state.lock();
+        if state.contexts.is_none() {
+            match (CCtx::try_create(), DCtx::try_create()) {
+                (Some(mut cctx), Some(mut dctx)) => {
+                    Zstd::warm_up(&mut cctx, &mut dctx, self.level);
+                    if let Some(dictionary) = state.dictionary.as_ref() {
+                        Self::warm_up(&mut cctx, &mut dctx, dictionary);
+                    }
+                    state.contexts = Some((cctx, dctx));
+                }
+                _ => tracing::error!("Could not allocate Zstd dictionary contexts for level {}", self.level),
+            }
+        }
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        zstd_safe::compress_bound(input_len)
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.with_state(|cctx, _, dictionary| match dictionary {
+            Some(dictionary) => cctx.compress_using_cdict(output, input, &dictionary.cdict),
+            None => cctx.compress(output, input, self.level),
+        })?
+        .map_err(|e| tracing::error!("Could not compress Zstd dictionary data: {}", zstd_safe::get_error_name(e)))
+        .ok()
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        let frame_dictionary = zstd_safe::get_dict_id_from_frame(input).map(|id| id.get());
+        self.with_state(|_, dctx, dictionary| {
+            let result = match (frame_dictionary, dictionary) {
+                (None, _) => dctx.decompress(output, input),
+                (Some(id), Some(dictionary)) if id == dictionary.id => dctx.decompress_using_ddict(output, input, &dictionary.ddict),
+                (Some(id), _) => {
+                    tracing::error!("Zstd frame needs dictionary {}, which isn't loaded", id);
+                    return None;
+                }
+            };
+            result
+                .map_err(|e| tracing::error!("Could not decompress Zstd dictionary data: {}", zstd_safe::get_error_name(e)))
+                .ok()
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{test_records as records, ZSTD};
+
+    #[test]
+    fn dictionary_round_trips_and_improves_small_frames() {
+        let codec = ZstdDict::new(ZstdDict::DEFAULT_LEVEL);
+        codec.preallocate();
+
+        let plain_frame = records(1, 256);
+        let before = codec.compress(&plain_frame).unwrap();
+
+        let mut samples = std::vec::Vec::new();
+        let mut sizes = std::vec::Vec::new();
+        for seed in (1000..200_000).step_by(1000) {
+            samples.extend_from_slice(&records(seed, 512));
+            sizes.push(512);
+        }
+        assert!(codec.train(&samples, &sizes).is_some());
+        assert!(codec.train(&samples, &sizes).is_none(), "a trained dictionary must not be replaced");
+
+        // Training warmed the contexts up for the dictionary
+        let sizes = || codec.with_state(|cctx, dctx, _| (cctx.sizeof(), dctx.sizeof())).unwrap();
+        let warm = sizes();
+        let frame = records(500_000, 256);
+        let with_dictionary = codec.compress(&frame).unwrap();
+        assert_eq!(sizes(), warm);
+        let without_dictionary = ZSTD.compress(&frame).unwrap();
+        assert!(with_dictionary.len() < without_dictionary.len());
+        assert_eq!(&codec.decompress(&with_dictionary, frame.len()).unwrap()[..], &frame[..]);
+
+        // Frames compressed before training still decompress
+        assert_eq!(&codec.decompress(&before, plain_frame.len()).unwrap()[..], &plain_frame[..]);
+    }
+}
@@ -0,0 +1,129 @@
+/// How many evenly spaced windows of a buffer are sampled by `estimate_compressibility`.
+pub const ESTIMATE_WINDOWS: usize = 16;
+/// The size of each sampled window.
+pub const ESTIMATE_WINDOW_SIZE: usize = 256;
+
+/// Samples with fewer bits of entropy per byte than this are predicted to compress.
+pub const MAX_COMPRESSIBLE_ENTROPY: f32 = 7.0;
+/// Samples where at least this fraction of 4-byte sequences repeat an earlier one
+/// are predicted to compress, whatever their entropy.
+pub const MIN_COMPRESSIBLE_REPEATS: f32 = 0.05;
+
+const REPEAT_TABLE_BITS: u32 = 10;
+
+/// A cheap guess at how well a buffer compresses, from a sample of its bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressibilityEstimate {
+    /// Shannon entropy of the sampled bytes, in bits per byte (0 to 8)
+    pub entropy: f32,
+    /// The fraction of sampled 4-byte sequences that repeat an earlier one
+    pub repeats: f32,
+}
+
+impl CompressibilityEstimate {
+    /// Whether running the codec on the buffer is likely to shrink it.
+    pub fn is_compressible(&self) -> bool {
+        self.entropy < MAX_COMPRESSIBLE_ENTROPY || self.repeats >= MIN_COMPRESSIBLE_REPEATS
+    }
+}
+
+/// Estimate how compressible `data` is without running a codec.
+///
+/// Up to `ESTIMATE_WINDOWS` windows of `ESTIMATE_WINDOW_SIZE` bytes, spread evenly
+/// over `data`, are sampled. The byte histogram of the sample gives its entropy, and
+/// a small hash table of 4-byte sequences catches repetition that a histogram can't
+/// see, such as repeated runs of otherwise random-looking bytes.
+pub fn estimate_compressibility(data: &[u8]) -> CompressibilityEstimate {
+    let mut histogram = [0u32; 256];
+    let mut table = [0u32; 1 << REPEAT_TABLE_BITS];
+    let mut sampled = 0usize;
+    let mut sequences = 0usize;
+    let mut repeats = 0usize;
+
+    let windows = data.len().div_ceil(ESTIMATE_WINDOW_SIZE).min(ESTIMATE_WINDOWS);
+    for w in 0..windows {
+        // Windows start at evenly spaced offsets, the last one ending at the end of `data`
+        let start = match windows {
+            1 => 0,
+            _ => w * (data.len() - ESTIMATE_WINDOW_SIZE.min(data.len())) / (windows - 1),
+        };
+        let window = &data[start..(start + ESTIMATE_WINDOW_SIZE).min(data.len())];
+
+        for &byte in window {
+            histogram[byte as usize] += 1;
+        }
+        sampled += window.len();
+
+        for sequence in window.windows(4) {
+            let sequence = u32::from_ne_bytes([sequence[0], sequence[1], sequence[2], sequence[3]]);
+            let slot = (sequence.wrapping_mul(2_654_435_761) >> (32 - REPEAT_TABLE_BITS)) as usize;
+            if table[slot] == sequence {
+                repeats += 1;
+            }
+            table[slot] = sequence;
+            sequences += 1;
+        }
+    }
+
+    let entropy = histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / sampled as f32;
+            -p * p.log2()
+        })
+        .sum();
+
+    CompressibilityEstimate {
+        entropy,
+        repeats: if sequences == 0 { 0.0 } else { repeats as f32 / sequences as f32 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes from a xorshift generator, which no codec can shrink.
+    fn random(len: usize) -> std::vec::Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_bytes_are_incompressible() {
+        let estimate = estimate_compressibility(&random(64 * 1024));
+        assert!(estimate.entropy > 7.5);
+        assert!(!estimate.is_compressible());
+    }
+
+    #[test]
+    fn zeroes_and_text_are_compressible() {
+        assert!(estimate_compressibility(&[0; 8192]).is_compressible());
+
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(200);
+        let estimate = estimate_compressibility(&text);
+        assert!(estimate.entropy < 5.0);
+        assert!(estimate.is_compressible());
+    }
+
+    #[test]
+    fn repeated_random_runs_are_compressible() {
+        // High entropy per byte, but every 1 KiB run occurs many times
+        let estimate = estimate_compressibility(&random(1024).repeat(64));
+        assert!(estimate.repeats >= MIN_COMPRESSIBLE_REPEATS);
+        assert!(estimate.is_compressible());
+    }
+
+    #[test]
+    fn empty_buffers_have_no_entropy() {
+        assert_eq!(estimate_compressibility(&[]), CompressibilityEstimate { entropy: 0.0, repeats: 0.0 });
+    }
+}
@@ -0,0 +1,217 @@
+use core::ffi::c_void;
+use heapless::Vec;
+
+use crate::{FAR_TIER_PATH, FAR_TIER_READ_ATTEMPTS};
+
+/// Freed extents of the far store remembered for reuse, neighbours merged; beyond
+/// this, freed space is leaked.
+pub const MAX_FAR_FREE_SLOTS: usize = 1024;
+
+/// Where an evicted payload lives in the `FarStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FarSlot {
+    offset: u64,
+    size: usize,
+}
+
+impl FarSlot {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.size as u64
+    }
+}
+
+/// A far tier for compressed payloads: a file, or a memfd if `FAR_TIER_PATH` is
+/// `None`, written and read back with `pwrite`/`pread`.
+///
+/// Nothing in it is mapped, so evicted payloads don't count towards the process's
+/// RSS at all. A memfd's pages still live in the page cache, like tmpfs, while a
+/// file on disk models slower far memory. The fd is opened lazily on first use.
+pub struct FarStore {
+    fd: i32,
+    /// The end of the furthest extent ever written
+    end: u64,
+    free: Vec<FarSlot, MAX_FAR_FREE_SLOTS>,
+    used: usize,
+    peak_used: usize,
+}
+
+impl FarStore {
+    pub const fn new() -> Self {
+        Self {
+            fd: -1,
+            end: 0,
+            free: Vec::new(),
+            used: 0,
+            peak_used: 0,
+        }
+    }
+
+    fn init(&mut self) -> Option<()> {
+        if self.fd >= 0 {
+            return Some(());
+        }
+        let fd = match FAR_TIER_PATH {
+            Some(path) => unsafe {
+                let fd = libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC, 0o600);
+                // Nobody else needs to find it, and it's cleaned up when the process exits
+                if fd >= 0 {
+                    libc::unlink(path.as_ptr());
+                }
+                fd
+            },
+            None => unsafe { libc::memfd_create(c"rust-compressor-far-tier".as_ptr(), libc::MFD_CLOEXEC) },
+        };
+        if fd < 0 {
+            tracing::error!("Could not open the far tier {:?}", FAR_TIER_PATH);
+            return None;
+        }
+        self.fd = fd;
+        Some(())
+    }
+
+    /// Take `size` bytes from the first freed extent big enough, or the end of the store.
+    fn allocate(&mut self, size: usize) -> FarSlot {
+        if let Some(i) = self.free.iter().position(|slot| slot.size >= size) {
+            let slot = self.free[i];
+            if slot.size == size {
+                self.free.swap_remove(i);
+            } else {
+                self.free[i] = FarSlot { offset: slot.offset + size as u64, size: slot.size - size };
+            }
+            return FarSlot { offset: slot.offset, size };
+        }
+        let slot = FarSlot { offset: self.end, size };
+        self.end += size as u64;
+        slot
+    }
+
+    /// Write `payload` to the store.
+    pub fn write(&mut self, payload: &[u8]) -> Option<FarSlot> {
+        self.init()?;
+        let slot = self.allocate(payload.len());
+        let written = unsafe { libc::pwrite(self.fd, payload.as_ptr() as *const c_void, payload.len(), slot.offset as libc::off_t) };
+        if written != payload.len() as isize {
+            tracing::error!("Could not write {} bytes to the far tier at {}", payload.len(), slot.offset);
+            self.used += slot.size;
+            self.free(slot);
+            return None;
+        }
+        self.used += slot.size;
+        self.peak_used = self.peak_used.max(self.used);
+        Some(slot)
+    }
+
+    /// Read the payload stored in `slot` into `buf`, which must be `slot.size()` bytes.
+    /// Interrupted and short reads are resumed, and failed ones tried again up to
+    /// `FAR_TIER_READ_ATTEMPTS` times in all.
+    pub fn read(&self, slot: &FarSlot, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        let mut attempts = 0;
+        while done < slot.size {
+            let rest = &mut buf[done..slot.size];
+            let offset = slot.offset + done as u64;
+            let read = unsafe { libc::pread(self.fd, rest.as_mut_ptr() as *mut c_void, rest.len(), offset as libc::off_t) };
+            if read > 0 {
+                done += read as usize;
+                continue;
+            }
+            let error = std::io::Error::last_os_error();
+            if read < 0 && error.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            attempts += 1;
+            if attempts >= FAR_TIER_READ_ATTEMPTS {
+                tracing::error!("Could not read {} bytes from the far tier at {}: {}", slot.size, slot.offset,
+                    if read < 0 { error.to_string() } else { "end of file".into() });
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return `slot` to the store, merging it with the free extents on either side.
+    /// Free space at the end of the store is given back to the end, so no free
+    /// extent ever ends there.
+    pub fn free(&mut self, slot: FarSlot) {
+        self.used -= slot.size;
+        let mut extent = slot;
+        while let Some(i) = self.free.iter().position(|free| free.end() == extent.offset || extent.end() == free.offset) {
+            let free = self.free.swap_remove(i);
+            extent = FarSlot { offset: extent.offset.min(free.offset), size: extent.size + free.size };
+        }
+        if extent.end() == self.end {
+            self.end = extent.offset;
+            return;
+        }
+        if self.free.push(extent).is_err() {
+            tracing::warn!("Too many free far tier extents, leaking {} bytes", extent.size);
+        }
+    }
+
+    /// Bytes currently holding payloads.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn peak_used(&self) -> usize {
+        self.peak_used
+    }
+
+    /// How far into the file or memfd payloads have been written.
+    pub fn size(&self) -> u64 {
+        self.end
+    }
+}
+
+impl Default for FarStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_round_trip_and_freed_space_is_reused() {
+        let mut store = FarStore::new();
+        let a = store.write(&[1u8; 100]).unwrap();
+        let b = store.write(&[2u8; 50]).unwrap();
+        assert_eq!((store.used(), store.size()), (150, 150));
+
+        let mut buf = [0u8; 50];
+        assert!(store.read(&b, &mut buf));
+        assert_eq!(buf, [2u8; 50]);
+
+        store.free(a);
+        let c = store.write(&[3u8; 60]).unwrap();
+        assert_eq!(store.size(), 150, "the freed extent should be reused");
+        let mut buf = [0u8; 60];
+        assert!(store.read(&c, &mut buf));
+        assert_eq!(buf, [3u8; 60]);
+
+        let past_end = FarSlot { offset: store.size() + 4096, size: 10 };
+        assert!(!store.read(&past_end, &mut [0u8; 10]), "reads past the end can't succeed however often they're tried");
+    }
+
+    #[test]
+    fn freed_extents_coalesce_and_shrink_the_store() {
+        let mut store = FarStore::new();
+        let slots = [10, 20, 30, 40].map(|size| store.write(&vec![0u8; size]).unwrap());
+        store.free(slots[0]);
+        store.free(slots[2]);
+        store.free(slots[1]);
+        assert_eq!(store.free.as_slice(), [FarSlot { offset: 0, size: 60 }], "neighbouring extents should merge");
+        let reused = store.write(&[1u8; 60]).unwrap();
+        assert_eq!((reused.offset, store.size()), (0, 100));
+
+        store.free(reused);
+        store.free(slots[3]);
+        assert_eq!((store.size(), store.free.len(), store.used()), (0, 0, 0), "free space at the end goes back to the end");
+    }
+}
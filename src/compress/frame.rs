@@ -0,0 +1,664 @@
+use core::ops::Range;
+
+use super::{compressor_by_id, open_payload, seal_payload, Compressor, CompressedPool, FarSlot, PayloadError, PayloadHeader, PoolSlot, MAX_COMPRESSED_SIZE};
+
+/// How one frame of a framed block is currently stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame {
+    /// The frame holds its original bytes
+    Resident,
+    /// The first `size` bytes of the frame hold its sealed payload, the rest is zeroed
+    Compressed { size: usize },
+    /// The sealed payload lives in the `CompressedPool`, and the `released` bytes
+    /// of whole pages inside the frame have been given back to the kernel
+    Pooled { slot: PoolSlot, released: usize },
+    /// Every word of the frame is `pattern`, so only the pattern is kept. When
+    /// compressing out of line, the `released` bytes of whole pages are given back
+    SameFilled { pattern: u64, released: usize },
+    /// The sealed payload was evicted from the pool to its far store, and the
+    /// `released` bytes of whole pages are still given back
+    Evicted { slot: FarSlot, released: usize },
+}
+
+/// A frame whose payload failed its checks on decompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptFrame {
+    /// The frame's index
+    pub frame: usize,
+    /// Where the frame starts within the block
+    pub offset: usize,
+    pub error: PayloadError,
+}
+
+/// A breakdown of how the frames of one or more blocks are stored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Bytes in frames that are entirely zero
+    pub zero_bytes: usize,
+    /// Bytes in frames filled with one repeated, non-zero word
+    pub same_filled_bytes: usize,
+    /// Original bytes in frames compressed by the codec
+    pub codec_original_bytes: usize,
+    /// What the codec compressed `codec_original_bytes` down to
+    pub codec_compressed_bytes: usize,
+    /// Bytes of whole pages given back to the kernel
+    pub released_bytes: usize,
+    /// Of `codec_compressed_bytes`, those evicted to the far store
+    pub evicted_bytes: usize,
+}
+
+impl core::ops::AddAssign for FrameStats {
+    fn add_assign(&mut self, other: Self) {
+        self.zero_bytes += other.zero_bytes;
+        self.same_filled_bytes += other.same_filled_bytes;
+        self.codec_original_bytes += other.codec_original_bytes;
+        self.codec_compressed_bytes += other.codec_compressed_bytes;
+        self.released_bytes += other.released_bytes;
+        self.evicted_bytes += other.evicted_bytes;
+    }
+}
+
+/// If `data` is one 8-byte word repeated (zero included), that word.
+pub fn fill_pattern(data: &[u8]) -> Option<u64> {
+    let mut word = [0u8; 8];
+    let prefix = data.len().min(8);
+    word[..prefix].copy_from_slice(&data[..prefix]);
+    if data.chunks(8).all(|chunk| chunk == &word[..chunk.len()]) {
+        Some(u64::from_ne_bytes(word))
+    } else {
+        None
+    }
+}
+
+/// Fill `data` with repetitions of `pattern`.
+pub fn fill_with_pattern(data: &mut [u8], pattern: u64) {
+    let word = pattern.to_ne_bytes();
+    for chunk in data.chunks_mut(8) {
+        chunk.copy_from_slice(&word[..chunk.len()]);
+    }
+}
+
+/// Compresses a block of any size as independent, fixed-size frames.
+///
+/// Each frame is compressed either in place within its own bytes, or out of line
+/// into a `CompressedPool`, so any frame can be decompressed without touching the
+/// others. Frames that don't shrink are left resident. The index records how each
+/// frame is stored.
+///
+/// Compressed frames are stored as sealed payloads (see `seal_payload`), so a frame
+/// that was overwritten while compressed is reported as a `CorruptFrame` instead of
+/// silently decompressing to the wrong bytes.
+///
+/// Frame boundaries fall on multiples of the frame size in the address space, so the
+/// first frame of a block that doesn't start on a boundary is shorter than the rest.
+///
+/// New frames are compressed with the index's codec, but each payload is opened with
+/// the codec its header names, so frames left behind by a change of codec, e.g.
+/// evicted ones, still decompress.
+#[derive(Clone)]
+pub struct FrameIndex {
+    compressor: &'static dyn Compressor,
+    frame_size: usize,
+    /// How far the data starts past a frame boundary
+    offset: usize,
+    frames: std::vec::Vec<Frame>,
+}
+
+impl FrameIndex {
+    /// An index for `len` bytes starting at address `start`, split into frames of
+    /// `frame_size` bytes, all resident.
+    pub fn new(compressor: &'static dyn Compressor, start: usize, len: usize, frame_size: usize) -> Self {
+        let offset = start % frame_size;
+        Self {
+            compressor,
+            frame_size,
+            offset,
+            frames: vec![Frame::Resident; (offset + len).div_ceil(frame_size)],
+        }
+    }
+
+    pub fn compressor(&self) -> &'static dyn Compressor {
+        self.compressor
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The byte range of `data` covered by frame `i`.
+    pub fn frame_range(&self, i: usize, len: usize) -> Range<usize> {
+        let start = (i * self.frame_size).saturating_sub(self.offset);
+        let end = ((i + 1) * self.frame_size - self.offset).min(len);
+        start..end
+    }
+
+    /// The frames overlapping the byte range `bytes`.
+    pub fn frames_covering(&self, bytes: Range<usize>) -> Range<usize> {
+        let start = (bytes.start + self.offset) / self.frame_size;
+        let end = (bytes.end + self.offset).div_ceil(self.frame_size).min(self.frames.len());
+        start.min(end)..end
+    }
+
+    /// The codec that sealed `payload`: the index's own, or the registered codec its
+    /// header names. An unknown codec is left to `open_payload` to reject.
+    fn codec_of(&self, payload: &[u8]) -> &'static dyn Compressor {
+        match PayloadHeader::from_bytes(payload) {
+            Some(header) if header.codec != self.compressor.id() => compressor_by_id(header.codec).unwrap_or(self.compressor),
+            _ => self.compressor,
+        }
+    }
+
+    /// Whether any frame is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.frames.iter().any(|frame| *frame != Frame::Resident)
+    }
+
+    /// The bytes of whole pages given back to the kernel by pooled frames.
+    pub fn released_size(&self) -> usize {
+        self.frames
+            .iter()
+            .map(|frame| match frame {
+                Frame::Pooled { released, .. } | Frame::SameFilled { released, .. } | Frame::Evicted { released, .. } => *released,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// How the frames of `len` bytes are stored.
+    pub fn stats(&self, len: usize) -> FrameStats {
+        let mut stats = FrameStats::default();
+        for (i, frame) in self.frames.iter().enumerate() {
+            let frame_len = self.frame_range(i, len).len();
+            match *frame {
+                Frame::Resident => {}
+                Frame::Compressed { size } => {
+                    stats.codec_original_bytes += frame_len;
+                    stats.codec_compressed_bytes += size;
+                }
+                Frame::Pooled { slot, released } => {
+                    stats.codec_original_bytes += frame_len;
+                    stats.codec_compressed_bytes += slot.size();
+                    stats.released_bytes += released;
+                }
+                Frame::Evicted { slot, released } => {
+                    stats.codec_original_bytes += frame_len;
+                    stats.codec_compressed_bytes += slot.size();
+                    stats.evicted_bytes += slot.size();
+                    stats.released_bytes += released;
+                }
+                Frame::SameFilled { pattern, released } => {
+                    if pattern == 0 {
+                        stats.zero_bytes += frame_len;
+                    } else {
+                        stats.same_filled_bytes += frame_len;
+                    }
+                    stats.released_bytes += released;
+                }
+            }
+        }
+        stats
+    }
+
+    /// The number of bytes the block occupies in memory with its compressed frames
+    /// compressed. Evicted frames take none.
+    pub fn stored_size(&self, len: usize) -> usize {
+        (0..self.frames.len())
+            .map(|i| match self.frames[i] {
+                Frame::Resident => self.frame_range(i, len).len(),
+                Frame::Compressed { size } => size,
+                Frame::Pooled { slot, .. } => slot.size(),
+                Frame::SameFilled { .. } | Frame::Evicted { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// Compress every resident frame of `data` in place. Same-filled frames are only
+    /// recorded, not run through the codec. Returns the number of frames compressed.
+    pub fn compress(&mut self, data: &mut [u8]) -> usize {
+        let mut compressed = 0;
+        for i in 0..self.frames.len() {
+            if self.frames[i] != Frame::Resident {
+                continue;
+            }
+            let range = self.frame_range(i, data.len());
+            if self.compress_frame(i, &mut data[range]) {
+                compressed += 1;
+            }
+        }
+        compressed
+    }
+
+    /// Compress resident frame `i` in place, given its bytes in `frame`. Only a frame
+    /// that ends up `Compressed` has its bytes changed. Returns whether it was compressed.
+    pub fn compress_frame(&mut self, i: usize, frame: &mut [u8]) -> bool {
+        if let Some(pattern) = fill_pattern(frame) {
+            self.frames[i] = Frame::SameFilled { pattern, released: 0 };
+            return true;
+        }
+        let Some(payload) = seal_payload(self.compressor, frame).filter(|payload| payload.len() < frame.len()) else {
+            return false;
+        };
+        frame[..payload.len()].copy_from_slice(&payload);
+        frame[payload.len()..].fill(0);
+        self.frames[i] = Frame::Compressed { size: payload.len() };
+        true
+    }
+
+    /// Compress every resident frame of `data` into `pool`, and release the whole
+    /// pages inside each compressed frame with `MADV_DONTNEED`. Same-filled frames
+    /// only keep their fill pattern and take no space in the pool.
+    /// Returns the number of frames compressed.
+    pub fn compress_to_pool(&mut self, data: &mut [u8], pool: &mut CompressedPool) -> usize {
+        let mut compressed = 0;
+        for i in 0..self.frames.len() {
+            if self.frames[i] != Frame::Resident {
+                continue;
+            }
+            let range = self.frame_range(i, data.len());
+            match self.stage_frame(i, &data[range], pool) {
+                Some(true) => compressed += 1,
+                Some(false) => {}
+                None => break,
+            }
+        }
+        self.release_staged(data);
+        compressed
+    }
+
+    /// Compress resident frame `i` into `pool`, given a copy of its bytes in `frame`,
+    /// but keep its pages until `release_staged`. Returns whether the frame was
+    /// compressed, or `None` if the pool is full.
+    pub fn stage_frame(&mut self, i: usize, frame: &[u8], pool: &mut CompressedPool) -> Option<bool> {
+        if let Some(pattern) = fill_pattern(frame) {
+            self.frames[i] = Frame::SameFilled { pattern, released: 0 };
+            return Some(true);
+        }
+        let Some(payload) = seal_payload(self.compressor, frame).filter(|payload| payload.len() < frame.len()) else {
+            return Some(false);
+        };
+        let slot = pool.store(&payload)?;
+        self.frames[i] = Frame::Pooled { slot, released: 0 };
+        Some(true)
+    }
+
+    /// Release the whole pages of every pooled or same-filled frame of `data` that
+    /// still holds them. Returns the bytes released.
+    pub fn release_staged(&mut self, data: &mut [u8]) -> usize {
+        let mut total = 0;
+        for i in 0..self.frames.len() {
+            let range = self.frame_range(i, data.len());
+            if let Frame::Pooled { released, .. } | Frame::SameFilled { released, .. } = &mut self.frames[i] {
+                if *released == 0 {
+                    *released = release_pages(&mut data[range]);
+                    total += *released;
+                }
+            }
+        }
+        total
+    }
+
+    /// Decompress the given frames of `data` in place, leaving the rest alone.
+    /// Stops at the first frame whose payload fails its checks, leaving it compressed.
+    pub fn decompress_frames(&mut self, data: &mut [u8], frames: Range<usize>, pool: &mut CompressedPool) -> Result<(), CorruptFrame> {
+        for i in frames {
+            let range = self.frame_range(i, data.len());
+            self.unstage_frame(i, range.start, &mut data[range], pool)?;
+        }
+        Ok(())
+    }
+
+    /// Decompress frame `i`, at `offset` into its block, into `frame`, which holds a
+    /// copy of the frame's current bytes, and mark it resident. Leaves resident frames
+    /// alone. An evicted frame that can't be read back stays evicted, as `PayloadError::Unreadable`.
+    pub fn unstage_frame(&mut self, i: usize, offset: usize, frame: &mut [u8], pool: &mut CompressedPool) -> Result<(), CorruptFrame> {
+        self.open_frame(i, offset, frame, pool)?;
+        self.mark_resident(i, pool);
+        Ok(())
+    }
+
+    /// Decompress frame `i`, at `offset` into its block, into `frame`, which holds a
+    /// copy of the frame's current bytes, without changing how the frame is stored.
+    /// Once the bytes are back in place, `mark_resident` lets go of the payload.
+    pub fn open_frame(&self, i: usize, offset: usize, frame: &mut [u8], pool: &CompressedPool) -> Result<(), CorruptFrame> {
+        let corrupt = |error| CorruptFrame { frame: i, offset, error };
+        match self.frames[i] {
+            Frame::Resident => {}
+            Frame::Compressed { size } => {
+                // The payload is overwritten as it's decompressed, so work from a copy
+                let payload = heapless::Vec::<u8, MAX_COMPRESSED_SIZE>::from_slice(&frame[..size])
+                    .map_err(|_| corrupt(PayloadError::Truncated { len: size }))?;
+                open_payload(self.codec_of(&payload), &payload, frame).map_err(corrupt)?;
+            }
+            Frame::Pooled { slot, .. } => {
+                let payload = pool.get(&slot);
+                open_payload(self.codec_of(payload), payload, frame).map_err(corrupt)?;
+            }
+            Frame::SameFilled { pattern, .. } => fill_with_pattern(frame, pattern),
+            Frame::Evicted { slot, .. } => {
+                let mut payload = heapless::Vec::<u8, MAX_COMPRESSED_SIZE>::new();
+                payload.resize_default(slot.size()).map_err(|_| corrupt(PayloadError::Truncated { len: slot.size() }))?;
+                if !pool.far().read(&slot, &mut payload) {
+                    return Err(corrupt(PayloadError::Unreadable { len: slot.size() }));
+                }
+                open_payload(self.codec_of(&payload), &payload, frame).map_err(corrupt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark frame `i` resident, freeing its payload in `pool` or the far store.
+    pub fn mark_resident(&mut self, i: usize, pool: &mut CompressedPool) {
+        match self.frames[i] {
+            Frame::Pooled { slot, .. } => pool.free(slot),
+            Frame::Evicted { slot, .. } => pool.far_mut().free(slot),
+            _ => {}
+        }
+        self.frames[i] = Frame::Resident;
+    }
+
+    /// Evict every pooled frame's payload to the pool's far store. Returns how many
+    /// frames were evicted; stops early if the far store can't be written.
+    pub fn evict(&mut self, pool: &mut CompressedPool) -> usize {
+        let mut evicted = 0;
+        for frame in self.frames.iter_mut() {
+            if let Frame::Pooled { slot, released } = *frame {
+                let Some(far_slot) = pool.evict(slot) else {
+                    break;
+                };
+                *frame = Frame::Evicted { slot: far_slot, released };
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    /// How many frames are evicted to the far store.
+    pub fn evicted_frames(&self) -> usize {
+        self.frames.iter().filter(|frame| matches!(frame, Frame::Evicted { .. })).count()
+    }
+
+    /// Recompress every compressed frame of `data` with `compressor`, which becomes the
+    /// index's codec, without touching the frames' pages unless they're stored in place.
+    /// A frame the new codec can't shrink is decompressed instead. Evicted frames, and
+    /// those after a corrupt one, keep their old codec. Returns how many fewer bytes
+    /// the frames take up.
+    pub fn recompress(&mut self, compressor: &'static dyn Compressor, data: &mut [u8], pool: &mut CompressedPool) -> Result<isize, CorruptFrame> {
+        self.set_compressor(compressor);
+        let mut saved = 0;
+        for i in 0..self.frames.len() {
+            let range = self.frame_range(i, data.len());
+            saved += self.recompress_frame(i, range.start, &mut data[range], pool)?;
+        }
+        Ok(saved)
+    }
+
+    /// Switch the codec new frames are compressed with to `compressor`. Frames already
+    /// compressed keep theirs until recompressed with `recompress_frame`.
+    pub fn set_compressor(&mut self, compressor: &'static dyn Compressor) {
+        self.compressor = compressor;
+    }
+
+    /// Recompress frame `i`, at `offset` into its block, with the index's codec.
+    /// `frame` holds a copy of the frame's current bytes; it's changed, and must be
+    /// written back, if the frame ends up `Compressed` or is decompressed to
+    /// `Resident`. Returns how many fewer bytes the frame takes up.
+    pub fn recompress_frame(&mut self, i: usize, offset: usize, frame: &mut [u8], pool: &mut CompressedPool) -> Result<isize, CorruptFrame> {
+        let corrupt = |error| CorruptFrame { frame: i, offset, error };
+        // Frames are never larger than a single payload can describe, so this only fails on a bad index
+        let mut original = heapless::Vec::<u8, MAX_COMPRESSED_SIZE>::new();
+        original
+            .resize_default(frame.len())
+            .map_err(|_| corrupt(PayloadError::WrongLength { expected: frame.len(), found: MAX_COMPRESSED_SIZE }))?;
+
+        let old_size = match self.frames[i] {
+            Frame::Resident | Frame::SameFilled { .. } | Frame::Evicted { .. } => return Ok(0),
+            Frame::Compressed { size } => {
+                open_payload(self.codec_of(&frame[..size]), &frame[..size], &mut original).map_err(corrupt)?;
+                size
+            }
+            Frame::Pooled { slot, .. } => {
+                let payload = pool.get(&slot);
+                open_payload(self.codec_of(payload), payload, &mut original).map_err(corrupt)?;
+                slot.size()
+            }
+        };
+
+        let payload = seal_payload(self.compressor, &original).filter(|payload| payload.len() < frame.len());
+        let stored = match (self.frames[i], payload) {
+            (Frame::Compressed { .. }, Some(payload)) => {
+                frame[..payload.len()].copy_from_slice(&payload);
+                frame[payload.len()..].fill(0);
+                self.frames[i] = Frame::Compressed { size: payload.len() };
+                Some(payload.len())
+            }
+            (Frame::Pooled { slot, released }, Some(payload)) => pool.store(&payload).map(|new_slot| {
+                pool.free(slot);
+                self.frames[i] = Frame::Pooled { slot: new_slot, released };
+                payload.len()
+            }),
+            _ => None,
+        };
+        match stored {
+            Some(size) => Ok(old_size as isize - size as isize),
+            None => {
+                frame.copy_from_slice(&original);
+                if let Frame::Pooled { slot, .. } = self.frames[i] {
+                    pool.free(slot);
+                }
+                self.frames[i] = Frame::Resident;
+                Ok(old_size as isize - frame.len() as isize)
+            }
+        }
+    }
+
+    /// Return every pooled or evicted frame's payload to `pool` without decompressing
+    /// it, e.g. when the block has been freed.
+    pub fn discard(&mut self, pool: &mut CompressedPool) {
+        for frame in self.frames.iter_mut() {
+            match *frame {
+                Frame::Pooled { slot, .. } => pool.free(slot),
+                Frame::Evicted { slot, .. } => pool.far_mut().free(slot),
+                _ => {}
+            }
+            *frame = Frame::Resident;
+        }
+    }
+
+    /// Decompress the frames covering the byte range `bytes` of `data`.
+    pub fn decompress_bytes(&mut self, data: &mut [u8], bytes: Range<usize>, pool: &mut CompressedPool) -> Result<(), CorruptFrame> {
+        let frames = self.frames_covering(bytes);
+        self.decompress_frames(data, frames, pool)
+    }
+
+    /// Decompress every frame of `data`.
+    pub fn decompress(&mut self, data: &mut [u8], pool: &mut CompressedPool) -> Result<(), CorruptFrame> {
+        self.decompress_frames(data, 0..self.frames.len(), pool)
+    }
+}
+
+/// Give the whole pages inside `data` back to the kernel. Their contents are
+/// gone afterwards; the next write faults in fresh pages. Returns the bytes released.
+fn release_pages(data: &mut [u8]) -> usize {
+    let page_size = crate::page_size();
+    let start = crate::mem::align_up_to_page_size(data.as_ptr() as usize, page_size);
+    let end = crate::mem::align_down_to_page_size(data.as_ptr() as usize + data.len(), page_size);
+    if end <= start {
+        return 0;
+    }
+    let ret = unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTNEED) };
+    if ret != 0 {
+        tracing::error!("Could not release {} bytes at 0x{:x}", end - start, start);
+        return 0;
+    }
+    end - start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{test_pattern, LZ4, ZSTD_STRONG};
+
+    #[test]
+    fn same_filled_frames_skip_the_codec() {
+        let mut data = test_pattern(4 * 4096);
+        data[..4096].fill(0);
+        fill_with_pattern(&mut data[4096..8192], 0xdead_beef_0bad_f00d);
+        let original = data.clone();
+
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        index.compress(&mut data);
+        assert_eq!(index.frames()[0], Frame::SameFilled { pattern: 0, released: 0 });
+        assert_eq!(index.frames()[1], Frame::SameFilled { pattern: 0xdead_beef_0bad_f00d, released: 0 });
+        assert!(matches!(index.frames()[2], Frame::Compressed { .. }));
+
+        let stats = index.stats(data.len());
+        assert_eq!(stats.zero_bytes, 4096);
+        assert_eq!(stats.same_filled_bytes, 4096);
+        assert_eq!(stats.codec_original_bytes, 2 * 4096);
+
+        index.decompress(&mut data, &mut CompressedPool::new()).unwrap();
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn blocks_larger_than_max_compressed_size_round_trip() {
+        let original = test_pattern(300_000);
+        let mut data = original.clone();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+
+        assert_eq!(index.compress(&mut data), index.frames().len());
+        assert!(index.stored_size(data.len()) < data.len());
+        index.decompress(&mut data, &mut CompressedPool::new()).unwrap();
+        assert!(!index.is_compressed());
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn decompressing_a_range_leaves_other_frames_compressed() {
+        let original = test_pattern(10 * 4096);
+        let mut data = original.clone();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        index.compress(&mut data);
+
+        index.decompress_bytes(&mut data, 5000..6000, &mut CompressedPool::new()).unwrap();
+        assert_eq!(&data[4096..8192], &original[4096..8192]);
+        assert_eq!(index.frames()[1], Frame::Resident);
+        assert!(matches!(index.frames()[0], Frame::Compressed { .. }));
+        assert!(matches!(index.frames()[2], Frame::Compressed { .. }));
+    }
+
+    #[test]
+    fn overwritten_frames_are_reported_as_corrupt() {
+        let original = test_pattern(4 * 4096);
+        let mut data = original.clone();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        index.compress(&mut data);
+
+        // Something writes to the third frame's payload while it's compressed
+        data[2 * 4096 + 20] ^= 0xff;
+        let corrupt = index.decompress(&mut data, &mut CompressedPool::new()).unwrap_err();
+        assert_eq!((corrupt.frame, corrupt.offset), (2, 2 * 4096));
+        assert!(matches!(index.frames()[2], Frame::Compressed { .. }));
+        assert_eq!(&data[..2 * 4096], &original[..2 * 4096]);
+    }
+
+    #[test]
+    fn recompressing_switches_every_frame_to_the_new_codec() {
+        let original = test_pattern(4 * 4096);
+        let mut data = original.clone();
+        let mut pool = CompressedPool::new();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        index.compress(&mut data);
+        let before = index.stored_size(data.len());
+
+        let saved = index.recompress(&ZSTD_STRONG, &mut data, &mut pool).unwrap();
+        assert_eq!(index.compressor().id(), ZSTD_STRONG.id());
+        assert_eq!(before as isize - saved, index.stored_size(data.len()) as isize);
+        assert!(saved > 0);
+
+        index.decompress(&mut data, &mut pool).unwrap();
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn evicted_frames_decompress_after_recompressing() {
+        let original = test_pattern(4 * 4096);
+        let mut data = original.clone();
+        let mut pool = CompressedPool::new();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        for i in 0..index.frames().len() {
+            let range = index.frame_range(i, data.len());
+            index.stage_frame(i, &data[range], &mut pool);
+        }
+        index.evict(&mut pool);
+        index.decompress_bytes(&mut data, 0..1, &mut pool).unwrap();
+        index.compress(&mut data);
+
+        index.recompress(&ZSTD_STRONG, &mut data, &mut pool).unwrap();
+        assert_eq!(index.evicted_frames(), 3, "evicted frames keep their codec");
+        index.decompress(&mut data, &mut pool).unwrap();
+        assert_eq!(data, original);
+        assert_eq!(pool.far().used(), 0);
+    }
+
+    #[test]
+    fn unaligned_start_shortens_the_first_frame() {
+        let index = FrameIndex::new(&LZ4, 4096 + 100, 10_000, 4096);
+        assert_eq!(index.frames().len(), 3);
+        assert_eq!(index.frame_range(0, 10_000), 0..3996);
+        assert_eq!(index.frame_range(1, 10_000), 3996..8092);
+        assert_eq!(index.frame_range(2, 10_000), 8092..10_000);
+        assert_eq!(index.frames_covering(4000..4001), 1..2);
+    }
+
+    #[test]
+    fn pooled_frames_release_pages_and_round_trip() {
+        let len = 8 * 4096;
+        let ptr = unsafe {
+            libc::mmap(core::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        } as *mut u8;
+        let data = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+        let original = test_pattern(len);
+        data.copy_from_slice(&original);
+
+        let mut pool = CompressedPool::new();
+        let mut index = FrameIndex::new(&LZ4, ptr as usize, len, 4096);
+        assert_eq!(index.compress_to_pool(data, &mut pool), 8);
+        assert_eq!(index.released_size(), len);
+        assert!(pool.used() > 0);
+
+        index.decompress(data, &mut pool).unwrap();
+        assert_eq!(&data[..], &original[..]);
+        assert_eq!(pool.used(), 0);
+    }
+
+    #[test]
+    fn evicted_frames_leave_the_pool_and_read_back() {
+        let original = test_pattern(4 * 4096);
+        let mut data = original.clone();
+        let mut pool = CompressedPool::new();
+        let mut index = FrameIndex::new(&LZ4, 0, data.len(), 4096);
+        // Stage the frames without releasing any pages, which the test still reads
+        for i in 0..index.frames().len() {
+            let range = index.frame_range(i, data.len());
+            assert_eq!(index.stage_frame(i, &data[range], &mut pool), Some(true));
+        }
+        let pooled = index.stats(data.len()).codec_compressed_bytes;
+
+        assert_eq!(index.evict(&mut pool), 4);
+        assert_eq!(pool.used(), 0);
+        assert_eq!(pool.far().used(), pooled);
+        assert_eq!(index.stored_size(data.len()), 0);
+        assert_eq!(index.stats(data.len()).evicted_bytes, pooled);
+
+        index.decompress_bytes(&mut data, 4096..4097, &mut pool).unwrap();
+        assert_eq!(&data[4096..8192], &original[4096..8192]);
+        assert_eq!(index.evicted_frames(), 3);
+        index.discard(&mut pool);
+        assert_eq!(pool.far().used(), 0);
+    }
+}
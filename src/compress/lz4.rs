@@ -0,0 +1,137 @@
+use spin::Mutex;
+
+use super::{Compressor, CompressorId};
+
+/// LZ4 block format, via `lz4_flex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lz4;
+
+pub static LZ4: Lz4 = Lz4;
+
+impl Lz4 {
+    pub const ID: CompressorId = 1;
+}
+
+impl Compressor for Lz4 {
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn id(&self) -> CompressorId {
+        Self::ID
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        lz4_flex::block::get_maximum_output_size(input_len)
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        lz4_flex::compress_into(input, output)
+            .map_err(|e| tracing::error!("Could not compress LZ4 data: {e:?}"))
+            .ok()
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        lz4_flex::decompress_into(input, output)
+            .map_err(|e| tracing::error!("Could not decompress LZ4 data: {e:?}"))
+            .ok()
+    }
+}
+
+// Links liblz4, which provides the HC functions declared below
+use lz4_sys as _;
+
+extern "C" {
+    // Not exposed by `lz4-sys`, but part of liblz4
+    fn LZ4_sizeofStateHC() -> core::ffi::c_int;
+    fn LZ4_compress_HC_extStateHC(
+        state: *mut core::ffi::c_void,
+        src: *const core::ffi::c_char,
+        dst: *mut core::ffi::c_char,
+        src_size: core::ffi::c_int,
+        dst_capacity: core::ffi::c_int,
+        compression_level: core::ffi::c_int,
+    ) -> core::ffi::c_int;
+}
+
+/// LZ4's high-compression mode, via liblz4.
+///
+/// Produces regular LZ4 blocks, so decompression is the same as `Lz4`. The HC
+/// match-finder state is allocated once by `preallocate` and reused for every call.
+pub struct Lz4Hc {
+    level: i32,
+    state: Mutex<Option<Box<[u64]>>>,
+}
+
+pub static LZ4_HC: Lz4Hc = Lz4Hc::new(Lz4Hc::DEFAULT_LEVEL);
+pub static LZ4_HC_MAX: Lz4Hc = Lz4Hc::new(Lz4Hc::MAX_LEVEL);
+
+impl Lz4Hc {
+    pub const ID: CompressorId = 6;
+    pub const DEFAULT_LEVEL: i32 = 9;
+    pub const MAX_LEVEL: i32 = 12;
+
+    pub const fn new(level: i32) -> Self {
+        Self {
+            level,
+            state: Mutex::new(None),
+        }
+    }
+
+    fn allocate_state() -> Box<[u64]> {
+        let words = (unsafe { LZ4_sizeofStateHC() } as usize).div_ceil(core::mem::size_of::<u64>());
+        vec![0u64; words].into_boxed_slice()
+    }
+}
+
+impl Compressor for Lz4Hc {
+    fn name(&self) -> &str {
+        "lz4hc"
+    }
+
+    fn id(&self) -> CompressorId {
+        Self::ID
+    }
+
+    fn level(&self) -> Option<i32> {
+        Some(self.level)
+    }
+
+    fn preallocate(&self) {
+        let mut state = self.state.lock();
+        if state.is_none() {
+            *state = Some(Self::allocate_state());
+        }
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        lz4_flex::block::get_maximum_output_size(input_len)
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        let mut state = self.state.lock();
+        if state.is_none() {
+            tracing::warn!("LZ4-HC level {} was not preallocated, allocating state now", self.level);
+        }
+        let state = state.get_or_insert_with(Self::allocate_state);
+        let compressed_size = unsafe {
+            LZ4_compress_HC_extStateHC(
+                state.as_mut_ptr() as *mut core::ffi::c_void,
+                input.as_ptr() as *const core::ffi::c_char,
+                output.as_mut_ptr() as *mut core::ffi::c_char,
+                input.len().try_into().ok()?,
+                output.len().min(i32::MAX as usize) as core::ffi::c_int,
+                self.level,
+            )
+        };
+        if compressed_size <= 0 {
+            tracing::error!("Could not compress LZ4-HC data");
+            return None;
+        }
+        Some(compressed_size as usize)
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        LZ4.decompress_into(input, output)
+    }
+}
@@ -1,94 +1,149 @@
 use heapless::Vec;
 
+pub mod lz4;
+pub use lz4::*;
+
+pub mod snappy;
+pub use snappy::*;
+
+pub mod zstd;
+pub use self::zstd::*;
+
+pub mod dict;
+pub use dict::*;
+
+pub mod deflate;
+pub use deflate::*;
+
+pub mod payload;
+pub use payload::*;
+
+pub mod pool;
+pub use pool::*;
+
+pub mod far;
+pub use far::*;
+
+pub mod frame;
+pub use frame::*;
+
+pub mod estimate;
+pub use estimate::*;
+
+pub mod registry;
+pub use registry::*;
+
+/// The most a single `compress`/`decompress` call can produce. Blocks larger
+/// than this are compressed as separate frames with a `FrameIndex`.
 pub const MAX_COMPRESSED_SIZE: usize = 65536;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CompressionAlgorithm {
-    LZ4,
-    Snappy,
-}
+/// A stable numeric identifier for a codec, e.g. for storing alongside compressed data.
+pub type CompressorId = u8;
+
+/// A block compression codec.
+///
+/// Implementors only need to compress and decompress into caller-provided buffers;
+/// everything else is built on top of that, so codecs never allocate on their own.
+pub trait Compressor: Send + Sync {
+    /// A short, lowercase name for the codec, e.g. `"lz4"`.
+    fn name(&self) -> &str;
+
+    /// The codec's unique ID in the registry. Every level of a codec shares one ID.
+    fn id(&self) -> CompressorId;
+
+    /// The compression level, for codecs that have one.
+    fn level(&self) -> Option<i32> {
+        None
+    }
+
+    /// Allocate any state the codec needs up front, so that compressing from inside
+    /// a hook never has to. Called when the codec is registered.
+    fn preallocate(&self) {}
 
-impl CompressionAlgorithm {
-    pub fn compress(&self, input: &impl AsRef<[u8]>) -> Option<heapless::Vec<u8, MAX_COMPRESSED_SIZE>> {
-        let input = input.as_ref();
-        let mut output = heapless::Vec::<u8, MAX_COMPRESSED_SIZE>::new();
+    /// The largest output `compress_into` can produce for `input_len` bytes of input.
+    fn max_compressed_size(&self, input_len: usize) -> usize;
 
-        match self {
-            CompressionAlgorithm::LZ4 => {
-                output.resize_default(lz4_flex::block::get_maximum_output_size(input.len())).ok()?;
-                let compressed_size = lz4_flex::compress_into(input, &mut output).ok()?;
+    /// Compress `input` into `output`, returning the compressed size.
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize>;
+
+    /// Decompress `input` into `output`, returning the decompressed size.
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize>;
+
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8, MAX_COMPRESSED_SIZE>> {
+        let mut output = Vec::<u8, MAX_COMPRESSED_SIZE>::new();
+        output.resize_default(self.max_compressed_size(input.len()).min(MAX_COMPRESSED_SIZE)).ok()?;
+        match self.compress_into(input, &mut output) {
+            Some(compressed_size) => {
                 output.truncate(compressed_size);
+                tracing::debug!("Compressed {} data: {} bytes", self.name(), compressed_size);
+                Some(output)
             }
-            CompressionAlgorithm::Snappy => {
-                let mut encoder = snap::raw::Encoder::new();
-                output.resize_default(MAX_COMPRESSED_SIZE).ok()?;
-                match encoder.compress(input, output.as_mut_slice()) {
-                    Ok(compressed_size) => {
-                        output.truncate(compressed_size);
-                        tracing::debug!("Compressed Snappy data: {} bytes", compressed_size);
-                    }
-                    Err(e) => {
-                        tracing::error!("Could not compress Snappy data: {e:?}");
-                        return None;
-                    }
-                }
+            None => {
+                tracing::error!("Could not compress {} data", self.name());
+                None
             }
         }
-
-        Some(output)
     }
 
-    pub fn compress_in_place(&self, input: &mut impl AsMut<[u8]>) -> Option<usize> {
-        let input = input.as_mut();
-        let compressed = self.compress(&input)?;
+    fn compress_in_place(&self, input: &mut [u8]) -> Option<usize> {
+        let compressed = self.compress(input)?;
+        if compressed.len() >= input.len() {
+            return None;
+        }
         input[..compressed.len()].copy_from_slice(&compressed);
         input[compressed.len()..].fill(0);
         Some(compressed.len())
     }
 
-    pub fn decompress(&self, input: &impl AsRef<[u8]>, compressed_size: usize) -> Option<Vec<u8, MAX_COMPRESSED_SIZE>> {
-        let input = input.as_ref();
+    /// Decompress `input` into a new buffer of at most `max_decompressed_size` bytes.
+    fn decompress(&self, input: &[u8], max_decompressed_size: usize) -> Option<Vec<u8, MAX_COMPRESSED_SIZE>> {
         let mut output = Vec::<u8, MAX_COMPRESSED_SIZE>::new();
-        match self {
-            CompressionAlgorithm::LZ4 => {
-                output.resize_default(lz4_flex::block::get_maximum_output_size(input.len())).ok()?;
-                let input = &input[..compressed_size];
-                match lz4_flex::decompress_into(input, &mut output) {
-                    Ok(decompressed_size) => {
-                        output.truncate(decompressed_size);
-                        tracing::debug!("Decompressed LZ4 data: {} bytes", decompressed_size);
-                    }
-                    Err(e) => {
-                        tracing::error!("Could not decompress LZ4 data: {e:?}");
-                        return None;
-                    }
-                }
+        output.resize_default(max_decompressed_size).ok()?;
+        match self.decompress_into(input, &mut output) {
+            Some(decompressed_size) => {
+                output.truncate(decompressed_size);
+                tracing::debug!("Decompressed {} data: {} bytes", self.name(), decompressed_size);
+                Some(output)
             }
-            CompressionAlgorithm::Snappy => {
-                let mut decoder = snap::raw::Decoder::new();
-                let input = &input[..compressed_size];
-                output.resize_default(snap::raw::decompress_len(input).ok()?).ok()?;
-                match decoder.decompress(input, output.as_mut_slice()) {
-                    Ok(decompressed_size) => {
-                        output.truncate(decompressed_size);
-                        tracing::debug!("Decompressed Snappy data: {} bytes", decompressed_size);
-                    }
-                    Err(e) => {
-                        tracing::error!("Could not decompress Snappy data: {e:?}");
-                        return None;
-                    }
-                }
+            None => {
+                tracing::error!("Could not decompress {} data", self.name());
+                None
             }
         }
-
-        Some(output)
     }
 
-    pub fn decompress_in_place(&self, input: &mut impl AsMut<[u8]>, compressed_size: usize) -> Option<usize> {
-        let input = input.as_mut();
-        let decompressed = self.decompress(&input, compressed_size)?;
+    fn decompress_in_place(&self, input: &mut [u8], compressed_size: usize) -> Option<usize> {
+        let decompressed = self.decompress(&input[..compressed_size], input.len())?;
         input[..decompressed.len()].copy_from_slice(&decompressed);
         input[decompressed.len()..].fill(0);
         Some(decompressed.len())
     }
-}
\ No newline at end of file
+}
+
+impl core::fmt::Debug for dyn Compressor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.level() {
+            Some(level) => write!(f, "{}:{}", self.name(), level),
+            None => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Compressible test data: runs of 64 bytes cycling through seven values.
+#[cfg(test)]
+pub(crate) fn test_pattern(len: usize) -> std::vec::Vec<u8> {
+    (0..len).map(|i| (i / 64 % 7) as u8).collect()
+}
+
+/// Small records that share field names but not values, like a heap of small objects.
+#[cfg(test)]
+pub(crate) fn test_records(seed: usize, len: usize) -> std::vec::Vec<u8> {
+    let mut data = std::vec::Vec::new();
+    let mut i = seed;
+    while data.len() < len {
+        data.extend_from_slice(format!("{{\"user_id\":{},\"session\":\"{:x}\",\"active\":{}}}", i * 7919, i * 104_729, i.is_multiple_of(3)).as_bytes());
+        i += 1;
+    }
+    data.truncate(len);
+    data
+}
@@ -0,0 +1,157 @@
+use core::fmt::{Display, Formatter, Result as FmtResult};
+use heapless::Vec;
+
+use super::{Compressor, CompressorId, MAX_COMPRESSED_SIZE};
+
+/// The size of the `PayloadHeader` in front of every sealed payload.
+pub const PAYLOAD_HEADER_SIZE: usize = 16;
+
+/// What a sealed payload decompresses to, checked by `open_payload`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadHeader {
+    pub codec: CompressorId,
+    pub original_len: u32,
+    /// `payload_checksum` of the original bytes
+    pub checksum: u64,
+}
+
+impl PayloadHeader {
+    pub fn to_bytes(&self) -> [u8; PAYLOAD_HEADER_SIZE] {
+        let mut bytes = [0u8; PAYLOAD_HEADER_SIZE];
+        bytes[0] = self.codec;
+        bytes[4..8].copy_from_slice(&self.original_len.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..PAYLOAD_HEADER_SIZE)?;
+        Some(Self {
+            codec: bytes[0],
+            original_len: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            checksum: u64::from_le_bytes(bytes[8..16].try_into().ok()?),
+        })
+    }
+}
+
+/// Why a sealed payload couldn't be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadError {
+    /// The payload is too short to hold a header
+    Truncated { len: usize },
+    /// The header names a different codec than the one decompressing it
+    WrongCodec { expected: CompressorId, found: CompressorId },
+    /// The header's original length doesn't match the space being decompressed into
+    WrongLength { expected: usize, found: usize },
+    /// The codec rejected the payload, or decompressed it to the wrong length
+    Codec,
+    /// The decompressed bytes don't match the header's checksum
+    ChecksumMismatch { expected: u64, found: u64 },
+    /// The payload couldn't be read back from the far tier
+    Unreadable { len: usize },
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Truncated { len } => write!(f, "payload of {len} bytes is too short for its header"),
+            Self::WrongCodec { expected, found } => write!(f, "payload is for codec {found}, expected codec {expected}"),
+            Self::WrongLength { expected, found } => write!(f, "payload holds {found} bytes, expected {expected}"),
+            Self::Codec => write!(f, "codec could not decompress the payload"),
+            Self::ChecksumMismatch { expected, found } => write!(f, "checksum 0x{found:016x} does not match 0x{expected:016x}"),
+            Self::Unreadable { len } => write!(f, "could not read {len} bytes back from the far tier"),
+        }
+    }
+}
+
+/// A fast checksum of `data`.
+pub fn payload_checksum(data: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(data)
+}
+
+/// Compress `input` with `compressor`, behind a `PayloadHeader` describing it.
+pub fn seal_payload(compressor: &dyn Compressor, input: &[u8]) -> Option<Vec<u8, MAX_COMPRESSED_SIZE>> {
+    let header = PayloadHeader {
+        codec: compressor.id(),
+        original_len: input.len().try_into().ok()?,
+        checksum: payload_checksum(input),
+    };
+    let mut output = Vec::<u8, MAX_COMPRESSED_SIZE>::new();
+    let len = (PAYLOAD_HEADER_SIZE + compressor.max_compressed_size(input.len())).min(MAX_COMPRESSED_SIZE);
+    output.resize_default(len).ok()?;
+    output[..PAYLOAD_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+    let Some(compressed_size) = compressor.compress_into(input, &mut output[PAYLOAD_HEADER_SIZE..]) else {
+        tracing::error!("Could not compress {} data", compressor.name());
+        return None;
+    };
+    output.truncate(PAYLOAD_HEADER_SIZE + compressed_size);
+    Some(output)
+}
+
+/// Decompress a payload made by `seal_payload` into all of `output`, checking its
+/// header against `compressor` and `output`, and the result against its checksum.
+pub fn open_payload(compressor: &dyn Compressor, payload: &[u8], output: &mut [u8]) -> Result<(), PayloadError> {
+    let header = PayloadHeader::from_bytes(payload).ok_or(PayloadError::Truncated { len: payload.len() })?;
+    if header.codec != compressor.id() {
+        return Err(PayloadError::WrongCodec { expected: compressor.id(), found: header.codec });
+    }
+    if header.original_len as usize != output.len() {
+        return Err(PayloadError::WrongLength { expected: output.len(), found: header.original_len as usize });
+    }
+
+    let decompressed = compressor.decompress_into(&payload[PAYLOAD_HEADER_SIZE..], output).ok_or(PayloadError::Codec)?;
+    if decompressed != output.len() {
+        return Err(PayloadError::Codec);
+    }
+    let checksum = payload_checksum(output);
+    if checksum != header.checksum {
+        return Err(PayloadError::ChecksumMismatch { expected: header.checksum, found: checksum });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::{test_pattern, LZ4, SNAPPY};
+
+    #[test]
+    fn sealed_payloads_round_trip() {
+        let input = test_pattern(4096);
+        let payload = seal_payload(&LZ4, &input).unwrap();
+        assert_eq!(PayloadHeader::from_bytes(&payload).unwrap().codec, LZ4.id());
+
+        let mut output = [0u8; 4096];
+        open_payload(&LZ4, &payload, &mut output).unwrap();
+        assert_eq!(&output[..], &input[..]);
+    }
+
+    #[test]
+    fn mismatched_headers_are_rejected() {
+        let payload = seal_payload(&LZ4, &test_pattern(4096)).unwrap();
+        let mut output = [0u8; 4096];
+
+        assert_eq!(open_payload(&SNAPPY, &payload, &mut output), Err(PayloadError::WrongCodec { expected: SNAPPY.id(), found: LZ4.id() }));
+        assert_eq!(open_payload(&LZ4, &payload, &mut output[..100]), Err(PayloadError::WrongLength { expected: 100, found: 4096 }));
+        assert_eq!(open_payload(&LZ4, &payload[..8], &mut output), Err(PayloadError::Truncated { len: 8 }));
+    }
+
+    #[test]
+    fn corrupted_payloads_are_detected() {
+        let input = test_pattern(4096);
+        let mut output = [0u8; 4096];
+        let sealed = seal_payload(&LZ4, &input).unwrap();
+
+        // Corrupt the first token and the trailing literals of the compressed data
+        for i in [PAYLOAD_HEADER_SIZE, sealed.len() - 1] {
+            let mut payload = sealed.clone();
+            payload[i] ^= 0x5a;
+            assert!(open_payload(&LZ4, &payload, &mut output).is_err(), "corrupting byte {i} went unnoticed");
+        }
+
+        let mut payload = sealed.clone();
+        payload[8] ^= 1;
+        assert!(matches!(open_payload(&LZ4, &payload, &mut output), Err(PayloadError::ChecksumMismatch { .. })));
+    }
+}
@@ -0,0 +1,138 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+use crate::{COMPRESSED_POOL_SIZE, mem::original_mmap};
+
+use super::{FarSlot, FarStore};
+
+/// Where a compressed payload lives in the `CompressedPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSlot {
+    ptr: NonNull<u8>,
+    size: usize,
+}
+
+impl PoolSlot {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size.max(1), 1).expect("Invalid pool slot layout")
+    }
+}
+
+unsafe impl Send for PoolSlot {}
+unsafe impl Sync for PoolSlot {}
+
+/// A store for compressed payloads, separate from the application's heap.
+///
+/// Like zsmalloc, it packs variable-sized payloads into one arena, which is
+/// mapped lazily with `COMPRESSED_POOL_SIZE` bytes and never tracked.
+///
+/// Payloads can be evicted further, into the pool's `FarStore`, which keeps them
+/// out of memory entirely.
+pub struct CompressedPool {
+    heap: Heap,
+    peak_used: usize,
+    far: FarStore,
+}
+
+pub static COMPRESSED_POOL: Mutex<CompressedPool> = Mutex::new(CompressedPool::new());
+
+impl CompressedPool {
+    pub const fn new() -> Self {
+        Self {
+            heap: Heap::empty(),
+            peak_used: 0,
+            far: FarStore::new(),
+        }
+    }
+
+    fn init(&mut self) -> Option<()> {
+        if self.heap.size() > 0 {
+            return Some(());
+        }
+        let arena = original_mmap(
+            core::ptr::null_mut(),
+            COMPRESSED_POOL_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if arena == libc::MAP_FAILED || arena.is_null() {
+            tracing::error!("Could not map {} bytes for the compressed pool", COMPRESSED_POOL_SIZE);
+            return None;
+        }
+        unsafe {
+            self.heap.init(arena as *mut u8, COMPRESSED_POOL_SIZE);
+        }
+        Some(())
+    }
+
+    /// Copy `payload` into the pool.
+    pub fn store(&mut self, payload: &[u8]) -> Option<PoolSlot> {
+        self.init()?;
+        let layout = Layout::from_size_align(payload.len().max(1), 1).ok()?;
+        let ptr = match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr,
+            Err(()) => {
+                tracing::warn!("Compressed pool is full, could not store {} bytes", payload.len());
+                return None;
+            }
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(payload.as_ptr(), ptr.as_ptr(), payload.len());
+        }
+        self.peak_used = self.peak_used.max(self.heap.used());
+        Some(PoolSlot { ptr, size: payload.len() })
+    }
+
+    /// The payload stored in `slot`.
+    pub fn get(&self, slot: &PoolSlot) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(slot.ptr.as_ptr(), slot.size) }
+    }
+
+    /// Return `slot` to the pool.
+    pub fn free(&mut self, slot: PoolSlot) {
+        unsafe {
+            self.heap.deallocate(slot.ptr, slot.layout());
+        }
+    }
+
+    /// Move the payload in `slot` out to the far store, freeing it in the pool.
+    /// Leaves it in the pool and returns `None` if it couldn't be written.
+    pub fn evict(&mut self, slot: PoolSlot) -> Option<FarSlot> {
+        let far_slot = self.far.write(unsafe { core::slice::from_raw_parts(slot.ptr.as_ptr(), slot.size) })?;
+        self.free(slot);
+        Some(far_slot)
+    }
+
+    pub fn far(&self) -> &FarStore {
+        &self.far
+    }
+
+    pub fn far_mut(&mut self) -> &mut FarStore {
+        &mut self.far
+    }
+
+    /// Bytes currently allocated to payloads, including allocator overhead.
+    pub fn used(&self) -> usize {
+        self.heap.used()
+    }
+
+    pub fn peak_used(&self) -> usize {
+        self.peak_used
+    }
+}
+
+impl Default for CompressedPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl Send for CompressedPool {}
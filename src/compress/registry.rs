@@ -0,0 +1,85 @@
+use heapless::Vec;
+use spin::RwLock;
+
+use super::{Compressor, CompressorId, DEFLATE, DEFLATE_STRONG, LZ4, LZ4_HC, LZ4_HC_MAX, SNAPPY, ZLIB, ZSTD, ZSTD_DICT, ZSTD_FAST, ZSTD_STRONG};
+
+pub const MAX_COMPRESSORS: usize = 32;
+
+lazy_static::lazy_static! {
+    static ref COMPRESSORS: RwLock<Vec<&'static dyn Compressor, MAX_COMPRESSORS>> = {
+        let mut compressors = Vec::new();
+        let builtin: [&'static dyn Compressor; 11] = [
+            &LZ4, &LZ4_HC, &LZ4_HC_MAX, &SNAPPY,
+            &ZSTD, &ZSTD_FAST, &ZSTD_STRONG, &ZSTD_DICT,
+            &DEFLATE, &DEFLATE_STRONG, &ZLIB,
+        ];
+        for compressor in builtin {
+            compressor.preallocate();
+            compressors.push(compressor).map_err(|_| ()).expect("Too many builtin compressors");
+        }
+        RwLock::new(compressors)
+    };
+}
+
+/// Add a codec to the registry.
+///
+/// Fails, returning the codec, if the registry is full or if a codec with the same
+/// name already uses a different ID, or the same ID is used by a differently-named codec.
+pub fn register_compressor(compressor: &'static dyn Compressor) -> Result<(), &'static dyn Compressor> {
+    let mut compressors = COMPRESSORS.write();
+    let conflicts = compressors.iter().any(|c| (c.id() == compressor.id()) != (c.name() == compressor.name()));
+    if conflicts {
+        tracing::error!("Compressor {:?} (ID {}) conflicts with a registered compressor", compressor, compressor.id());
+        return Err(compressor);
+    }
+    compressor.preallocate();
+    compressors.push(compressor)
+}
+
+/// Every registered codec, in registration order.
+pub fn compressors() -> Vec<&'static dyn Compressor, MAX_COMPRESSORS> {
+    COMPRESSORS.read().clone()
+}
+
+/// Look up the first registered codec with the given name.
+pub fn compressor_by_name(name: &str) -> Option<&'static dyn Compressor> {
+    COMPRESSORS.read().iter().copied().find(|c| c.name() == name)
+}
+
+/// Look up a registered codec by name and level, e.g. `("zstd", Some(19))`.
+pub fn compressor_by_name_and_level(name: &str, level: Option<i32>) -> Option<&'static dyn Compressor> {
+    COMPRESSORS.read().iter().copied().find(|c| c.name() == name && c.level() == level)
+}
+
+/// Look up the first registered codec with the given ID.
+pub fn compressor_by_id(id: CompressorId) -> Option<&'static dyn Compressor> {
+    COMPRESSORS.read().iter().copied().find(|c| c.id() == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_every_compressor() {
+        let mut input = [0u8; 4096];
+        for (i, byte) in input.iter_mut().enumerate() {
+            *byte = (i % 17) as u8;
+        }
+
+        for compressor in compressors() {
+            let compressed = compressor.compress(&input).expect("compression failed");
+            let decompressed = compressor.decompress(&compressed, input.len()).expect("decompression failed");
+            assert_eq!(&decompressed[..], &input[..], "{:?} did not round trip", compressor);
+        }
+    }
+
+    #[test]
+    fn lookup_by_name_and_id() {
+        for compressor in compressors() {
+            assert_eq!(compressor_by_name(compressor.name()).map(|c| c.id()), Some(compressor.id()));
+            assert_eq!(compressor_by_id(compressor.id()).map(|c| c.name()), Some(compressor.name()));
+            assert!(compressor_by_name_and_level(compressor.name(), compressor.level()).is_some());
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use super::{Compressor, CompressorId};
+
+/// Raw Snappy, via `snap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snappy;
+
+pub static SNAPPY: Snappy = Snappy;
+
+impl Snappy {
+    pub const ID: CompressorId = 2;
+}
+
+impl Compressor for Snappy {
+    fn name(&self) -> &str {
+        "snappy"
+    }
+
+    fn id(&self) -> CompressorId {
+        Self::ID
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        snap::raw::max_compress_len(input_len)
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        snap::raw::Encoder::new()
+            .compress(input, output)
+            .map_err(|e| tracing::error!("Could not compress Snappy data: {e:?}"))
+            .ok()
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        snap::raw::Decoder::new()
+            .decompress(input, output)
+            .map_err(|e| tracing::error!("Could not decompress Snappy data: {e:?}"))
+            .ok()
+    }
+}
@@ -0,0 +1,154 @@
+use spin::Mutex;
+use zstd_safe::{CCtx, DCtx};
+
+use super::{Compressor, CompressorId};
+use crate::{page_size, COMPRESSION_FRAME_PAGES};
+
+/// Zstandard at a fixed compression level, via `zstd-safe`.
+///
+/// Negative levels are zstd's "fast" levels. Levels outside the range the library
+/// supports are clamped to it, so `level` reports the level frames are really
+/// compressed at. The compression and decompression contexts are allocated once by `preallocate`, workspaces included, and reused
+/// for every call.
+pub struct Zstd {
+    level: i32,
+    contexts: Mutex<Option<(CCtx<'static>, DCtx<'static>)>>,
+}
+
+pub static ZSTD: Zstd = Zstd::new(Zstd::DEFAULT_LEVEL);
+pub static ZSTD_FAST: Zstd = Zstd::new(-5);
+pub static ZSTD_STRONG: Zstd = Zstd::new(19);
+
+impl Zstd {
+    pub const ID: CompressorId = 3;
+    pub const DEFAULT_LEVEL: i32 = zstd_safe::CLEVEL_DEFAULT;
+    /// `zstd_safe::min_c_level()`, which isn't const: the fastest level is
+    /// `-ZSTD_TARGETLENGTH_MAX`.
+    pub const MIN_LEVEL: i32 = -(1 << 17);
+    /// `zstd_safe::max_c_level()`, which isn't const.
+    pub const MAX_LEVEL: i32 = 22;
+
+    /// Compress at `level`, clamped to `MIN_LEVEL..=MAX_LEVEL`.
+    pub const fn new(level: i32) -> Self {
+        Self {
+            level: Self::clamp_level(level),
+            contexts: Mutex::new(None),
+        }
+    }
+
+    /// Clamp `level` to the levels zstd supports. zstd clamps out-of-range levels
+    /// itself when compressing, but the level we report should match.
+    pub(super) const fn clamp_level(level: i32) -> i32 {
+        if level < Self::MIN_LEVEL {
+            Self::MIN_LEVEL
+        } else if level > Self::MAX_LEVEL {
+            Self::MAX_LEVEL
+        } else {
+            level
+        }
+    }
+
+    /// Round-trip a frame through fresh contexts. Creating a context allocates only
+    /// its shell; the workspace for the level and input size is allocated on first
+    /// use, so this keeps that off the hot path. Smaller inputs reuse the workspace.
+    pub(super) fn warm_up(cctx: &mut CCtx<'static>, dctx: &mut DCtx<'static>, level: i32) {
+        let frame = Self::warm_up_frame();
+        let mut compressed = std::vec::Vec::with_capacity(zstd_safe::compress_bound(frame.len()));
+        let mut decompressed = std::vec::Vec::with_capacity(frame.len());
+        let round_trip = cctx.compress(&mut compressed, &frame, level)
+            .and_then(|_| dctx.decompress(&mut decompressed, &compressed));
+        if let Err(e) = round_trip {
+            tracing::error!("Could not warm up Zstd level {}: {}", level, zstd_safe::get_error_name(e));
+        }
+    }
+
+    /// A frame-sized buffer to warm contexts up with.
+    pub(super) fn warm_up_frame() -> std::vec::Vec<u8> {
+        (0..COMPRESSION_FRAME_PAGES * page_size()).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn with_contexts<T>(&self, f: impl FnOnce(&mut CCtx<'static>, &mut DCtx<'static>) -> T) -> Option<T> {
+        let mut contexts = self.contexts.lock();
+        if contexts.is_none() {
+            tracing::warn!("Zstd level {} was not preallocated, allocating contexts now", self.level);
+            *contexts = Some((CCtx::try_create()?, DCtx::try_create()?));
+        }
+        let (cctx, dctx) = contexts.as_mut()?;
+        Some(f(cctx, dctx))
+    }
+}
+
+impl Compressor for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn id(&self) -> CompressorId {
+        Self::ID
+    }
+
+    fn level(&self) -> Option<i32> {
+        Some(self.level)
+    }
+
+    fn preallocate(&self) {
+        let mut contexts = self.contexts.lock();
+        if contexts.is_none() {
+            match (CCtx::try_create(), DCtx::try_create()) {
+                (Some(mut cctx), Some(mut dctx)) => {
+                    Self::warm_up(&mut cctx, &mut dctx, self.level);
+                    *contexts = Some((cctx, dctx));
+                }
+                _ => tracing::error!("Could not allocate Zstd contexts for level {}", self.level),
+            }
+        }
+    }
+
+    fn max_compressed_size(&self, input_len: usize) -> usize {
+        zstd_safe::compress_bound(input_len)
+    }
+
+    fn compress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.with_contexts(|cctx, _| cctx.compress(output, input, self.level))?
+            .map_err(|e| tracing::error!("Could not compress Zstd data: {}", zstd_safe::get_error_name(e)))
+            .ok()
+    }
+
+    fn decompress_into(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        self.with_contexts(|_, dctx| dctx.decompress(output, input))?
+            .map_err(|e| tracing::error!("Could not decompress Zstd data: {}", zstd_safe::get_error_name(e)))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preallocated_contexts_dont_grow_on_first_use() {
+        let codec = Zstd::new(Zstd::DEFAULT_LEVEL);
+        codec.preallocate();
+        let frame = (0..COMPRESSION_FRAME_PAGES * page_size()).map(|i| (i / 7) as u8).collect::<std::vec::Vec<u8>>();
+        let sizes = || codec.with_contexts(|cctx, dctx| (cctx.sizeof(), dctx.sizeof())).unwrap();
+
+        let before = sizes();
+        let compressed = codec.compress(&frame).unwrap();
+        assert_eq!(&codec.decompress(&compressed, frame.len()).unwrap()[..], &frame[..]);
+        assert_eq!(sizes(), before);
+    }
+
+    #[test]
+    fn out_of_range_levels_are_clamped() {
+        assert_eq!(Zstd::MIN_LEVEL, zstd_safe::min_c_level());
+        assert_eq!(Zstd::MAX_LEVEL, zstd_safe::max_c_level());
+
+        for (level, clamped) in [(i32::MIN, Zstd::MIN_LEVEL), (100, Zstd::MAX_LEVEL), (-5, -5)] {
+            let codec = Zstd::new(level);
+            assert_eq!(codec.level(), Some(clamped));
+            let frame = Zstd::warm_up_frame();
+            let compressed = codec.compress(&frame).unwrap();
+            assert_eq!(&codec.decompress(&compressed, frame.len()).unwrap()[..], &frame[..]);
+        }
+    }
+}
@@ -1,11 +1,75 @@
-use crate::interval::IntervalTestConfig;
+use crate::interval::{AgingPolicy, FarLatency, HeatPolicy, IntervalClock, IntervalTestConfig};
 
 pub const ALIGN_ALLOCATIONS_TO_PAGE_SIZE: bool = true;
 
 pub const UNPROTECT_READ_WRITE_ON_FAULT: bool = false;
 
+/// Single-step the faulting instruction with the x86-64 trap flag and
+/// re-protect the page afterwards, so that every access is observed instead
+/// of only the first one per interval. Very slow; meant for small runs.
+pub const SINGLE_STEP_TRACING: bool = false;
+
 pub const INTERVAL_CONFIG: IntervalTestConfig = IntervalTestConfig {
-    interval_ms: 1000,
+    clock: IntervalClock::WallTime { ms: 1000 },
 };
 
+/// Also run the interval schedule from a timer thread every this many milliseconds,
+/// so that programs which stop calling `malloc`/`free` still get new intervals.
+/// `None` leaves scheduling entirely to the allocation hooks and the fault handler.
+pub const INTERVAL_TIMER_MS: Option<u64> = None;
+
+/// Blocks are compressed as independent frames of this many pages, so that a
+/// fault only has to decompress the frames covering the faulting page.
+pub const COMPRESSION_FRAME_PAGES: usize = 1;
+
+/// Which blocks `CompressAlloc` considers cold enough to compress, by default.
+pub const COMPRESSION_AGING_POLICY: AgingPolicy = AgingPolicy::IdleIntervals(2);
+
+/// How many intervals `DictionaryTraining` samples heap frames for before training
+/// the dictionary.
+pub const DICTIONARY_TRAINING_INTERVALS: u64 = 3;
+
+/// Abort the process when a compressed frame fails its checksum or header checks on
+/// decompression, instead of only logging it and leaving the frame compressed.
+pub const ABORT_ON_CORRUPT_FRAME: bool = true;
+
+/// The size of the arena that out-of-line compressed payloads are stored in.
+pub const COMPRESSED_POOL_SIZE: usize = 256 * 1024 * 1024;
+
+/// The file that `CompressAlloc::with_far_tier` evicts compressed payloads to, or
+/// `None` for an anonymous memfd. The file is unlinked as soon as it's opened.
+pub const FAR_TIER_PATH: Option<&core::ffi::CStr> = None;
+// pub const FAR_TIER_PATH: Option<&core::ffi::CStr> = Some(c"/tmp/rust-compressor-far-tier");
+
+/// How many times a failed read from the far tier is tried before the faulting
+/// access is left to fault again. Interrupted and short reads don't count.
+pub const FAR_TIER_READ_ATTEMPTS: usize = 3;
+
+/// What `FarMemoryEmulation` charges each access fault on a far block, by default.
+pub const FAR_MEMORY_LATENCY: FarLatency = FarLatency::Spin { ns: 2_000 };
+
+/// Which blocks `FarMemoryEmulation` demotes to far memory, by default.
+pub const FAR_MEMORY_AGING_POLICY: AgingPolicy = AgingPolicy::IdleIntervals(2);
+
+/// How many faults a far block takes within one interval before it counts as hot
+/// and is promoted back to near memory.
+pub const FAR_MEMORY_PROMOTION_FAULTS: usize = 4;
+
+/// How `HeatClassification` rates blocks by default: over the last 16 intervals,
+/// blocks accessed in neither of the last 2 are cold, and of the rest, those
+/// accessed in at least 4 intervals are hot.
+pub const HEAT_POLICY: HeatPolicy = HeatPolicy { window: 16, recent: 2, hot_intervals: 4 };
+
+/// `LifetimeAnalysis` flags allocation sites whose blocks live this many
+/// microseconds or less on average as short-lived.
+pub const SHORT_LIVED_LIFETIME_US: u64 = 1000;
+
+/// `LifetimeAnalysis` flags allocation sites that have never freed a block, once
+/// one of their live blocks is this many intervals old.
+pub const NEVER_FREED_INTERVALS: u64 = 10;
+
+/// How many read faults a block not written since its first read must take for
+/// `AccessPatternAnalysis` to count it as write-once-read-many.
+pub const READ_MANY_FAULTS: usize = 4;
+
 pub const MAX_TRACKED_ALLOCATIONS: usize = 1024;
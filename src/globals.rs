@@ -1,22 +1,23 @@
 use spin::RwLock;
-use crate::interval::{CompressAlloc, DummyCompressIntervalTest, DummyIntervalTest, IntervalTest};
+use crate::interval::{CompressAlloc, IntervalTest};
 
 use super::track::{Track, Block};
 use super::interval::{IntervalTestSuite};
-use super::compress::CompressionAlgorithm;
+use super::compress;
+use super::site::AllocationSite;
 use super::MAX_TRACKED_ALLOCATIONS;
 
 pub static TRACK: RwLock<Track<MAX_TRACKED_ALLOCATIONS>> = RwLock::new(Track::new());
 
-pub fn track_allocation(ptr: *mut u8, size: usize) -> Result<bool, Block> {
-    TRACK.write().insert(Block::new(ptr, size))
+pub fn track_allocation(ptr: *mut u8, size: usize, site: AllocationSite) -> Result<bool, Block> {
+    TRACK.write().insert(Block::new(ptr, size).with_site(site))
 }
 
 pub fn get_tracked_allocation(ptr: *const u8) -> Option<Block> {
     TRACK.read().get(ptr)
 }
 
-pub fn track_deallocation(ptr: *const u8) -> Result<Block, ()> {
+pub fn track_deallocation(ptr: *const u8) -> Option<Block> {
     TRACK.write().remove_ptr(ptr)
 }
 
@@ -27,10 +28,27 @@ pub fn get_tracked_allocations<>() -> Track<MAX_TRACKED_ALLOCATIONS> {
 lazy_static::lazy_static! {
     static ref INTERVAL_TEST_SUITE: RwLock<IntervalTestSuite> = RwLock::new(IntervalTestSuite::from_tests(&[
         // DummyIntervalTest.boxed(),
-        // DummyCompressIntervalTest(CompressionAlgorithm::LZ4).boxed()
-        // DummyCompressIntervalTest(CompressionAlgorithm::Snappy).boxed()
-        // CompressAlloc::new(CompressionAlgorithm::Snappy).boxed()
-        CompressAlloc::new(CompressionAlgorithm::LZ4).boxed()
+        // DummyCompressIntervalTest(&compress::LZ4).boxed()
+        // DummyCompressIntervalTest(&compress::SNAPPY).boxed()
+        // CompressAlloc::new(&compress::SNAPPY).boxed()
+        // CompressAlloc::new(&compress::ZSTD_STRONG).boxed()
+        // CompressAlloc::new(&compress::LZ4_HC).boxed()
+        // CompressAlloc::new(&compress::DEFLATE).boxed()
+        // CompressAlloc::new(&compress::LZ4).with_aging(AgingPolicy::Lru { resident_bytes: 64 * 1024 * 1024 }).boxed()
+        // CompressAlloc::new(&compress::LZ4).with_tier(&compress::ZSTD_STRONG, 8).boxed()
+        // CompressAlloc::new(&compress::LZ4).in_background().boxed()
+        // CompressAlloc::new(&compress::LZ4).with_far_tier(8).boxed()
+        // AccessTrace::new().boxed()
+        // DedupAnalysis::new().with_sharing(&compress::LZ4).boxed()
+        // CodecSurvey::new().boxed()
+        // DictionaryTraining::new().boxed(), CompressAlloc::new(&compress::ZSTD_DICT).boxed()
+        // FarMemoryEmulation::new().with_latency(FarLatency::Sleep { ns: 50_000 }).boxed()
+        // WorkingSetSize::new().boxed()
+        // HeatClassification::new().boxed(), CompressAlloc::new(&compress::LZ4).with_aging(AgingPolicy::Heat { at_most: Heat::Cold }).boxed()
+        // ReuseDistance::new().boxed()
+        // LifetimeAnalysis::new().boxed()
+        // AccessPatternAnalysis::new().boxed()
+        CompressAlloc::new(&compress::LZ4).boxed()
     ]));
 }
 
@@ -0,0 +1,178 @@
+use crate::{track::Block, MAX_TRACKED_ALLOCATIONS};
+use heapless::{FnvIndexMap as IndexMap, FnvIndexSet as IndexSet, Vec};
+use super::{block_heat, Heat};
+
+/// How `AccessAging` decides which blocks have gone cold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgingPolicy {
+    /// Every block is cold at every interval
+    Always,
+    /// Second-chance CLOCK: each interval, a block accessed since the last one has
+    /// its reference bit cleared and stays hot; a block without it is cold
+    Clock,
+    /// A block is cold once it goes this many intervals without an access
+    IdleIntervals(u64),
+    /// Keep the most recently accessed blocks hot, up to `resident_bytes` in total;
+    /// every other block is cold
+    Lru { resident_bytes: usize },
+    /// A block is cold once `HeatClassification` rates it `at_most` this warm;
+    /// blocks it hasn't classified stay hot
+    Heat { at_most: Heat },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockAge {
+    last_access: u64,
+    referenced: bool,
+}
+
+/// Tracks when each block was last accessed, to tell hot blocks from cold ones.
+#[derive(Debug, Clone)]
+pub struct AccessAging {
+    policy: AgingPolicy,
+    interval: u64,
+    ages: IndexMap<*const u8, BlockAge, MAX_TRACKED_ALLOCATIONS>,
+}
+
+impl AccessAging {
+    pub fn new(policy: AgingPolicy) -> Self {
+        Self {
+            policy,
+            interval: 0,
+            ages: IndexMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> AgingPolicy {
+        self.policy
+    }
+
+    /// Record an access to `block` (or its allocation) during the current interval.
+    pub fn touch(&mut self, block: &Block) {
+        let age = BlockAge {
+            last_access: self.interval,
+            referenced: true,
+        };
+        if self.ages.insert(block.ptr(), age).is_err() {
+            tracing::warn!("Too many blocks to age {:?}", block);
+        }
+    }
+
+    pub fn forget(&mut self, block: &Block) {
+        self.ages.remove(&block.ptr());
+    }
+
+    /// How many intervals `block` has gone without an access.
+    pub fn idle_intervals(&self, block: &Block) -> u64 {
+        self.ages.get(&block.ptr()).map_or(self.interval, |age| self.interval - age.last_access)
+    }
+
+    /// End the current interval, returning which of `blocks` are cold under the policy.
+    pub fn sweep<'a>(&mut self, blocks: impl Iterator<Item = &'a Block>) -> IndexSet<*const u8, MAX_TRACKED_ALLOCATIONS> {
+        let mut cold = IndexSet::new();
+        match self.policy {
+            AgingPolicy::Always => {
+                for block in blocks {
+                    let _ = cold.insert(block.ptr());
+                }
+            }
+            AgingPolicy::Clock => {
+                for block in blocks {
+                    match self.ages.get_mut(&block.ptr()) {
+                        Some(age) if age.referenced => age.referenced = false,
+                        _ => {
+                            let _ = cold.insert(block.ptr());
+                        }
+                    }
+                }
+            }
+            AgingPolicy::IdleIntervals(intervals) => {
+                for block in blocks {
+                    if self.idle_intervals(block) >= intervals {
+                        let _ = cold.insert(block.ptr());
+                    }
+                }
+            }
+            AgingPolicy::Lru { resident_bytes } => {
+                // Most recently accessed first
+                let mut recency = blocks
+                    .map(|block| (self.idle_intervals(block), block.ptr(), block.size()))
+                    .collect::<Vec<_, MAX_TRACKED_ALLOCATIONS>>();
+                recency.sort_unstable_by_key(|&(idle, _, _)| idle);
+
+                let mut resident = 0;
+                for (_, ptr, size) in recency {
+                    if resident + size <= resident_bytes {
+                        resident += size;
+                    } else {
+                        let _ = cold.insert(ptr);
+                    }
+                }
+            }
+            AgingPolicy::Heat { at_most } => {
+                for block in blocks {
+                    if block_heat(block).is_some_and(|heat| heat <= at_most) {
+                        let _ = cold.insert(block.ptr());
+                    }
+                }
+            }
+        }
+        self.interval += 1;
+        cold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks() -> [Block; 3] {
+        [
+            Block::new(0x1000 as *mut u8, 4096),
+            Block::new(0x3000 as *mut u8, 4096),
+            Block::new(0x5000 as *mut u8, 8192),
+        ]
+    }
+
+    #[test]
+    fn idle_blocks_go_cold_after_the_threshold() {
+        let blocks = blocks();
+        let mut aging = AccessAging::new(AgingPolicy::IdleIntervals(2));
+        blocks.iter().for_each(|block| aging.touch(block));
+
+        assert!(aging.sweep(blocks.iter()).is_empty());
+        aging.touch(&blocks[0]);
+        assert!(aging.sweep(blocks.iter()).is_empty());
+        let cold = aging.sweep(blocks.iter());
+        assert!(!cold.contains(&blocks[0].ptr()));
+        assert!(cold.contains(&blocks[1].ptr()) && cold.contains(&blocks[2].ptr()));
+    }
+
+    #[test]
+    fn clock_gives_referenced_blocks_a_second_chance() {
+        let blocks = blocks();
+        let mut aging = AccessAging::new(AgingPolicy::Clock);
+        blocks.iter().for_each(|block| aging.touch(block));
+
+        assert!(aging.sweep(blocks.iter()).is_empty());
+        aging.touch(&blocks[1]);
+        let cold = aging.sweep(blocks.iter());
+        assert_eq!(cold.len(), 2);
+        assert!(!cold.contains(&blocks[1].ptr()));
+    }
+
+    #[test]
+    fn lru_keeps_the_most_recent_blocks_within_budget() {
+        let blocks = blocks();
+        let mut aging = AccessAging::new(AgingPolicy::Lru { resident_bytes: 8192 });
+        aging.touch(&blocks[2]);
+        aging.sweep(blocks.iter());
+        aging.touch(&blocks[0]);
+        aging.touch(&blocks[1]);
+
+        // The two 4 KiB blocks were accessed last and fill the budget
+        let cold = aging.sweep(blocks.iter());
+        assert_eq!(cold.len(), 1);
+        assert!(cold.contains(&blocks[2].ptr()));
+    }
+}
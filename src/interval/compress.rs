@@ -1,48 +1,572 @@
-use crate::{MAX_TRACKED_ALLOCATIONS, compress::CompressionAlgorithm, globals::get_tracked_allocations, track::Block};
-use heapless::FnvIndexMap as IndexMap;
-use super::IntervalTest;
+use crate::{COMPRESSION_AGING_POLICY, MAX_TRACKED_ALLOCATIONS, compress::{estimate_compressibility, CompressedPool, Compressor, Frame, FrameIndex, FrameStats, COMPRESSED_POOL}, globals::get_tracked_allocations, resident_set_size, state::{self, BlockState}, track::Block, worker};
+use heapless::{FnvIndexMap as IndexMap, FnvIndexSet as IndexSet, Vec};
+use super::{AccessAging, AgingPolicy, IntervalTest};
+use std::time::Instant;
 use tracing::*;
 
+/// Every this many blocks judged incompressible, the codec is run on the block's
+/// first frame anyway, to measure how often the estimator is wrong.
+pub const INCOMPRESSIBLE_CHECK_EVERY: usize = 8;
+
+/// How the compressibility estimator's predictions turned out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EstimatorStats {
+    /// Blocks predicted to compress and handed to the codec
+    pub predicted_compressible: usize,
+    /// Of those, blocks where the codec couldn't shrink a single frame
+    pub wrongly_compressible: usize,
+    /// Blocks predicted not to compress, and skipped until they're written to
+    pub predicted_incompressible: usize,
+    /// Of those, blocks whose first frame was run through the codec to check
+    pub checked_incompressible: usize,
+    /// Of the checked blocks, those whose first frame did shrink
+    pub wrongly_incompressible: usize,
+    /// Bytes of blocks skipped without looking at them again, because they were
+    /// already known to be incompressible
+    pub skipped_bytes: usize,
+}
+
+impl EstimatorStats {
+    /// The fraction of checked predictions that were right.
+    pub fn accuracy(&self) -> f64 {
+        let checked = self.predicted_compressible + self.checked_incompressible;
+        let wrong = self.wrongly_compressible + self.wrongly_incompressible;
+        if checked == 0 {
+            1.0
+        } else {
+            (checked - wrong) as f64 / checked as f64
+        }
+    }
+}
+
+/// How the aging policy's choices turned out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AgingStats {
+    /// Times a block was cold at an interval and handed to the codec
+    pub cold: usize,
+    /// Times a block was still hot at an interval and left alone
+    pub hot: usize,
+    /// Faults on compressed blocks, each of which had to decompress a page
+    pub misses: usize,
+    /// Misses during the current interval
+    pub interval_misses: usize,
+}
+
+impl AgingStats {
+    /// Misses per cold block compressed.
+    pub fn miss_rate(&self) -> f64 {
+        self.misses as f64 / self.cold.max(1) as f64
+    }
+}
+
+/// Evictions to the far tier and read-backs from it, for one block or all of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FarStats {
+    /// Frames evicted to the far store
+    pub evictions: usize,
+    /// Compressed bytes those frames took up
+    pub evicted_bytes: usize,
+    pub evict_ns: u64,
+    /// Frames read back from the far store on a fault
+    pub read_backs: usize,
+    pub read_back_ns: u64,
+}
+
+impl FarStats {
+    /// Mean time to evict a frame, in microseconds.
+    pub fn evict_us(&self) -> f64 {
+        self.evict_ns as f64 / 1000.0 / self.evictions.max(1) as f64
+    }
+
+    /// Mean time to read a frame back and decompress it, in microseconds.
+    pub fn read_back_us(&self) -> f64 {
+        self.read_back_ns as f64 / 1000.0 / self.read_backs.max(1) as f64
+    }
+}
+
+impl core::ops::AddAssign for FarStats {
+    fn add_assign(&mut self, other: Self) {
+        self.evictions += other.evictions;
+        self.evicted_bytes += other.evicted_bytes;
+        self.evict_ns += other.evict_ns;
+        self.read_backs += other.read_backs;
+        self.read_back_ns += other.read_back_ns;
+    }
+}
+
+pub const MAX_COMPRESSION_TIERS: usize = 4;
+
+/// A codec that blocks are moved to once they've been idle long enough.
+#[derive(Clone, Copy)]
+pub struct CompressionTier {
+    pub codec: &'static dyn Compressor,
+    /// How many intervals a block must go without an access to enter this tier
+    pub idle_intervals: u64,
+    /// Blocks recompressed into this tier so far
+    pub promotions: usize,
+    /// How many fewer bytes those blocks took up after being recompressed
+    pub promoted_savings: isize,
+}
+
+impl CompressionTier {
+    fn new(codec: &'static dyn Compressor, idle_intervals: u64) -> Self {
+        Self {
+            codec,
+            idle_intervals,
+            promotions: 0,
+            promoted_savings: 0,
+        }
+    }
+
+    fn uses(&self, codec: &dyn Compressor) -> bool {
+        self.codec.id() == codec.id() && self.codec.level() == codec.level()
+    }
+}
+
+/// Compresses tracked blocks once they go cold, and decompresses on access.
+///
+/// By default the compressed frames are moved out of line into the
+/// `COMPRESSED_POOL` and the original pages are released, so the savings show
+/// up in the process's RSS. `in_place` keeps the old behavior of compressing
+/// within the block itself.
+///
+/// Before a new block is run through the codec, a sample of its bytes is checked
+/// with `estimate_compressibility`. Blocks that look incompressible, such as
+/// encrypted or already compressed data, are skipped and remembered until they're
+/// written to. `without_estimator` compresses every block regardless.
+///
+/// Which blocks count as cold is up to the `AgingPolicy`, `COMPRESSION_AGING_POLICY`
+/// unless set with `with_aging`. A fault on a compressed block counts as a miss:
+/// the block was compressed too early.
+///
+/// Cold blocks are compressed with the codec given to `new`. `with_tier` adds
+/// stronger, slower codecs that blocks are recompressed with once they stay idle
+/// for longer. A block stays in its tier after a miss; frames faulted back in are
+/// compressed with the tier's codec again when the block next goes cold.
+///
+/// `in_background` hands cold blocks to the `worker` thread instead of compressing
+/// them on whichever application thread ran the interval. The block stays protected
+/// while the worker has it; a fault on it takes it back, cancelling the job if the
+/// worker hasn't started it, or waiting for the worker to finish its current frame.
+///
+/// Every block moves through the `BlockState`s as it's compressed and decompressed,
+/// so a thread touching a block another thread is busy with waits for it to settle.
+///
+/// `with_far_tier` evicts the pooled frames of blocks that stay idle even longer to
+/// the pool's `FarStore`, out of memory entirely. A fault reads the frames it needs
+/// back synchronously. Evictions and read-backs, and how long they took, are
+/// counted for each block.
 #[derive(Clone)]
 pub struct CompressAlloc {
-    algo: CompressionAlgorithm,
-    compressed_sizes: IndexMap<*const u8, usize, MAX_TRACKED_ALLOCATIONS>,
+    algo: &'static dyn Compressor,
+    /// The first tier is `algo`, for every block that's cold at all
+    tiers: Vec<CompressionTier, MAX_COMPRESSION_TIERS>,
+    out_of_line: bool,
+    background: bool,
+    estimate: bool,
+    compressed: IndexMap<*const u8, FrameIndex, MAX_TRACKED_ALLOCATIONS>,
+    incompressible: IndexSet<*const u8, MAX_TRACKED_ALLOCATIONS>,
+    estimator: EstimatorStats,
+    aging: AccessAging,
+    aging_stats: AgingStats,
+    /// How many intervals a compressed block must go idle before it's evicted
+    far_tier: Option<u64>,
+    far: IndexMap<*const u8, FarStats, MAX_TRACKED_ALLOCATIONS>,
+    far_total: FarStats,
 }
 
 impl CompressAlloc {
-    pub fn new(algo: CompressionAlgorithm) -> Self {
+    pub fn new(algo: &'static dyn Compressor) -> Self {
+        algo.preallocate();
+        let mut tiers = Vec::new();
+        let _ = tiers.push(CompressionTier::new(algo, 0));
         Self {
             algo,
-            compressed_sizes: IndexMap::new(),
+            tiers,
+            out_of_line: true,
+            background: false,
+            estimate: true,
+            compressed: IndexMap::new(),
+            incompressible: IndexSet::new(),
+            estimator: EstimatorStats::default(),
+            aging: AccessAging::new(COMPRESSION_AGING_POLICY),
+            aging_stats: AgingStats::default(),
+            far_tier: None,
+            far: IndexMap::new(),
+            far_total: FarStats::default(),
+        }
+    }
+
+    /// Compress blocks within their own memory instead of into the compressed pool.
+    pub fn in_place(mut self) -> Self {
+        self.out_of_line = false;
+        self.background = false;
+        self
+    }
+
+    /// Compress cold blocks into the compressed pool on the worker thread.
+    pub fn in_background(mut self) -> Self {
+        self.out_of_line = true;
+        self.background = true;
+        self
+    }
+
+    /// Run every new block through the codec, without estimating its compressibility first.
+    pub fn without_estimator(mut self) -> Self {
+        self.estimate = false;
+        self
+    }
+
+    /// Only compress blocks that are cold under `policy`.
+    pub fn with_aging(mut self, policy: AgingPolicy) -> Self {
+        self.aging = AccessAging::new(policy);
+        self
+    }
+
+    /// Recompress blocks with `codec` once they've gone `idle_intervals` intervals
+    /// without an access.
+    pub fn with_tier(mut self, codec: &'static dyn Compressor, idle_intervals: u64) -> Self {
+        codec.preallocate();
+        if self.tiers.push(CompressionTier::new(codec, idle_intervals)).is_err() {
+            error!("Too many compression tiers, ignoring {:?}", codec);
+        }
+        self.tiers.sort_unstable_by_key(|tier| tier.idle_intervals);
+        self
+    }
+
+    /// Evict the compressed frames of blocks that have gone `idle_intervals` intervals
+    /// without an access to the far tier. Only frames in the compressed pool are
+    /// evicted, so this has no effect with `in_place`.
+    pub fn with_far_tier(mut self, idle_intervals: u64) -> Self {
+        self.far_tier = Some(idle_intervals);
+        self
+    }
+
+    /// The far tier's counters for `block`.
+    pub fn far_stats(&self, block: &Block) -> FarStats {
+        self.far.get(&block.ptr()).copied().unwrap_or_default()
+    }
+
+    /// The far tier's counters across every block so far, freed ones included.
+    pub fn far_total(&self) -> FarStats {
+        self.far_total
+    }
+
+    /// Record `stats` for `block`, as well as in the totals.
+    fn count_far(&mut self, block: &Block, stats: FarStats) {
+        self.far_total += stats;
+        match self.far.get_mut(&block.ptr()) {
+            Some(block_stats) => *block_stats += stats,
+            None => {
+                if self.far.insert(block.ptr(), stats).is_err() {
+                    warn!("    Too many blocks to count far tier accesses for {:?}", block);
+                }
+            }
+        }
+    }
+
+    /// Evict `block`'s pooled frames to the far tier if it's been idle long enough.
+    fn evict(&mut self, block: &Block, index: &mut FrameIndex) {
+        let Some(far_tier) = self.far_tier else {
+            return;
+        };
+        let idle = self.aging.idle_intervals(block);
+        if idle < far_tier || !index.frames().iter().any(|frame| matches!(frame, Frame::Pooled { .. })) {
+            return;
+        }
+        let before = index.stats(block.size()).evicted_bytes;
+        let start = Instant::now();
+        let evictions = index.evict(&mut COMPRESSED_POOL.lock());
+        let evict_ns = start.elapsed().as_nanos() as u64;
+        if evictions > 0 {
+            let evicted_bytes = index.stats(block.size()).evicted_bytes - before;
+            info!("    Evicted {} frames ({} bytes) of block: {:?} after {} idle intervals", evictions, evicted_bytes, block, idle);
+            self.count_far(block, FarStats { evictions, evicted_bytes, evict_ns, ..FarStats::default() });
+        }
+    }
+
+    pub fn tiers(&self) -> &[CompressionTier] {
+        &self.tiers
+    }
+
+    /// The tier whose codec compressed `index`.
+    fn tier_of(&self, index: &FrameIndex) -> usize {
+        self.tiers.iter().position(|tier| tier.uses(index.compressor())).unwrap_or(0)
+    }
+
+    /// Recompress `block` into a later tier if it's been idle long enough for one.
+    fn promote(&mut self, block: &mut Block, index: &mut FrameIndex) {
+        let idle = self.aging.idle_intervals(block);
+        let current = self.tier_of(index);
+        let Some(target) = self.tiers.iter().rposition(|tier| idle >= tier.idle_intervals) else {
+            return;
+        };
+        if target <= current {
+            return;
+        }
+
+        let tier = &mut self.tiers[target];
+        state::begin(block, BlockState::Compressing);
+        let saved = block.recompress(index, tier.codec);
+        state::finish(block, BlockState::settled(index.is_compressed()));
+        if let Some(saved) = saved {
+            tier.promotions += 1;
+            tier.promoted_savings += saved;
+            info!("    Recompressed block: {:?} with {:?} after {} idle intervals, saving {} bytes", block, tier.codec, idle, saved);
+        }
+    }
+
+    pub fn aging_stats(&self) -> AgingStats {
+        self.aging_stats
+    }
+
+    pub fn estimator_stats(&self) -> EstimatorStats {
+        self.estimator
+    }
+
+    /// Predict whether `block` is worth compressing. Blocks predicted not to be are
+    /// remembered, and every `INCOMPRESSIBLE_CHECK_EVERY`th one is checked against the codec.
+    fn is_worth_compressing(&mut self, block: &Block) -> bool {
+        let estimate = estimate_compressibility(block.as_bytes());
+        if estimate.is_compressible() {
+            self.estimator.predicted_compressible += 1;
+            return true;
+        }
+
+        debug!("    Skipping incompressible block: {:?} ({:.2} bits/byte, {:.1}% repeats)", block, estimate.entropy, estimate.repeats * 100.0);
+        self.estimator.predicted_incompressible += 1;
+        if self.estimator.predicted_incompressible % INCOMPRESSIBLE_CHECK_EVERY == 1 {
+            let range = block.frame_index(self.algo).frame_range(0, block.size());
+            let shrinks = self.algo.compress(&block.as_bytes()[range.clone()]).is_some_and(|payload| payload.len() < range.len());
+            self.estimator.checked_incompressible += 1;
+            if shrinks {
+                self.estimator.wrongly_incompressible += 1;
+            }
+        }
+        if self.incompressible.insert(block.ptr()).is_err() {
+            warn!("    Too many incompressible blocks to remember {:?}", block);
+        }
+        false
+    }
+
+    fn compress_frames(&self, block: &mut Block, index: &mut FrameIndex) -> usize {
+        state::begin(block, BlockState::Compressing);
+        // Other threads may be running on the block's pages; from here on they fault and wait
+        block.protect();
+        let compressed = if self.out_of_line {
+            block.compress_frames_out_of_line(index)
+        } else {
+            block.compress_frames(index)
+        };
+        state::finish(block, BlockState::settled(index.is_compressed()));
+        compressed
+    }
+
+    pub fn report(&self) {
+        let tracked = get_tracked_allocations();
+        let mut stats = FrameStats::default();
+        let mut original = 0;
+        for (ptr, index) in self.compressed.iter() {
+            if let Some(block) = tracked.get(*ptr) {
+                original += block.size();
+                stats += index.stats(block.size());
+            }
+        }
+        let pool = COMPRESSED_POOL.lock();
+        let percent = |bytes: usize| if original == 0 { 0.0 } else { 100.0 * bytes as f64 / original as f64 };
+        info!("    {} compressed blocks totalling {} bytes", self.compressed.len(), original);
+        info!("    Zero-filled: {} bytes ({:.1}%), same-filled: {} bytes ({:.1}%)",
+            stats.zero_bytes, percent(stats.zero_bytes), stats.same_filled_bytes, percent(stats.same_filled_bytes));
+        // With several tiers the codec bytes are broken down by tier below
+        let codecs = if self.tiers.len() < 2 { format!("{:?}", self.algo) } else { "All tiers".into() };
+        info!("    {}: {} bytes ({:.1}%) compressed to {} bytes (ratio {:.2})",
+            codecs, stats.codec_original_bytes, percent(stats.codec_original_bytes), stats.codec_compressed_bytes,
+            stats.codec_original_bytes as f64 / stats.codec_compressed_bytes.max(1) as f64);
+        self.report_tiers();
+        info!("    Released {} bytes, compressed pool uses {} bytes (peak {})", stats.released_bytes, pool.used(), pool.peak_used());
+        if self.far_tier.is_some() {
+            self.report_far(&stats, &pool);
+        }
+        info!("    Net savings: {} bytes, process RSS: {:?} bytes", stats.released_bytes as isize - pool.used() as isize, resident_set_size());
+        let aging = &self.aging_stats;
+        info!("    Aging ({:?}): {} cold and {} hot blocks so far, {} misses ({} this interval), {:.2} misses per compression",
+            self.aging.policy(), aging.cold, aging.hot, aging.misses, aging.interval_misses, aging.miss_rate());
+        if self.background {
+            let stats = worker::worker_stats();
+            info!("    Worker: {} blocks submitted, {} compressed, {} cancelled before starting, {} stopped early",
+                stats.submitted, stats.completed, stats.cancelled, stats.stopped);
+        }
+        if self.estimate {
+            let estimator = &self.estimator;
+            info!("    Estimator: {} blocks predicted compressible ({} wrongly), {} incompressible ({} of {} checked wrongly)",
+                estimator.predicted_compressible, estimator.wrongly_compressible, estimator.predicted_incompressible,
+                estimator.wrongly_incompressible, estimator.checked_incompressible);
+            info!("    Estimator: {} incompressible blocks remembered, {} bytes skipped, accuracy {:.1}%",
+                self.incompressible.len(), estimator.skipped_bytes, estimator.accuracy() * 100.0);
+        }
+    }
+
+    /// Log what the far tier holds, and the evictions and read-backs of each block.
+    fn report_far(&self, stats: &FrameStats, pool: &CompressedPool) {
+        let far = pool.far();
+        let total = &self.far_total;
+        info!("    Far tier: {} bytes evicted now, store uses {} bytes (peak {}) of {} written",
+            stats.evicted_bytes, far.used(), far.peak_used(), far.size());
+        info!("    Far tier: {} frames evicted ({:.1} us each), {} read back ({:.1} us each)",
+            total.evictions, total.evict_us(), total.read_backs, total.read_back_us());
+        for (ptr, block) in self.far.iter() {
+            debug!("        Block {:?}: {} frames evicted ({} bytes, {:.1} us each), {} read back ({:.1} us each)",
+                ptr, block.evictions, block.evicted_bytes, block.evict_us(), block.read_backs, block.read_back_us());
+        }
+    }
+
+    /// Log how many bytes sit in each tier, and what recompressing into it saved.
+    fn report_tiers(&self) {
+        if self.tiers.len() < 2 {
+            return;
+        }
+        let tracked = get_tracked_allocations();
+        let mut tiers = Vec::<(usize, FrameStats), MAX_COMPRESSION_TIERS>::new();
+        let _ = tiers.resize_default(self.tiers.len());
+        for (ptr, index) in self.compressed.iter() {
+            if let Some(block) = tracked.get(*ptr) {
+                let (blocks, stats) = &mut tiers[self.tier_of(index)];
+                *blocks += 1;
+                *stats += index.stats(block.size());
+            }
+        }
+        for (i, (tier, (blocks, stats))) in self.tiers.iter().zip(tiers.iter()).enumerate() {
+            info!("    Tier {} ({:?}, idle for {}+ intervals): {} blocks, {} bytes compressed to {} bytes (ratio {:.2}), {} promotions saved {} bytes",
+                i, tier.codec, tier.idle_intervals, blocks, stats.codec_original_bytes, stats.codec_compressed_bytes,
+                stats.codec_original_bytes as f64 / stats.codec_compressed_bytes.max(1) as f64, tier.promotions, tier.promoted_savings);
         }
     }
 
     pub fn is_compressed(&self, block: &Block) -> bool {
-        self.compressed_sizes.contains_key(&block.ptr())
+        self.compressed.contains_key(&block.ptr())
+    }
+
+    /// Take back the blocks the worker has finished with.
+    fn collect_finished(&mut self) {
+        worker::drain_finished(|block, index, frames| {
+            if frames > 0 {
+                info!("    Compressed block in the background: {:?} to {} bytes with {:?}", block, index.stored_size(block.size()), self.algo);
+            }
+            if index.is_compressed() && self.compressed.insert(block.ptr(), index).is_err() {
+                error!("    Too many compressed blocks to track {:?}", block);
+            }
+        });
+    }
+
+    /// Take `block` back from the worker, if it has it.
+    fn take_from_worker(&mut self, block: &Block) {
+        if !self.background {
+            return;
+        }
+        if let Some(index) = worker::take_job(block) {
+            if index.is_compressed() && self.compressed.insert(block.ptr(), index).is_err() {
+                error!("    Too many compressed blocks to track {:?}", block);
+            }
+        }
     }
 
+    /// Compress every block that's cold under the aging policy.
     pub fn compress_all_allocations(&mut self) {
+        if self.background {
+            self.collect_finished();
+        }
         let tracked = get_tracked_allocations();
+        let cold = self.aging.sweep(tracked.iter());
         for mut block in tracked.into_iter() {
-            if let Some(compressed_size) = block.compress(self.algo) {
-                info!("    Compressed block: {:?} to {} bytes", block, compressed_size);
-                self.compressed_sizes.insert(block.ptr(), compressed_size).unwrap();
+            if self.background && worker::job_state(&block).is_some() {
+                block.protect();
+                continue;
+            }
+            if !cold.contains(&block.ptr()) {
+                self.aging_stats.hot += 1;
+                block.protect();
+                continue;
+            }
+            self.aging_stats.cold += 1;
+            if self.incompressible.contains(&block.ptr()) {
+                self.estimator.skipped_bytes += block.size();
                 block.protect();
+                continue;
             }
+            // Blocks with compressed frames have already shown they compress, so only new blocks are estimated
+            let known = self.compressed.remove(&block.ptr());
+            let estimated = known.is_none() && self.estimate;
+            if estimated && !self.is_worth_compressing(&block) {
+                block.protect();
+                continue;
+            }
+
+            let mut index = known.unwrap_or_else(|| block.frame_index(self.algo));
+            if self.background {
+                if index.stats(block.size()).codec_original_bytes > 0 {
+                    self.promote(&mut block, &mut index);
+                }
+                self.evict(&block, &mut index);
+                if !index.frames().contains(&Frame::Resident) {
+                    self.compressed.insert(block.ptr(), index).ok();
+                    block.protect();
+                    continue;
+                }
+                // The worker only reads the block, so it can be protected as soon as it's queued
+                block.protect();
+                match worker::submit(block, index) {
+                    Ok(()) => continue,
+                    Err(returned) => {
+                        // Fall back to compressing it here
+                        block.unprotect();
+                        index = returned;
+                    }
+                }
+            }
+            // Only resident frames get compressed, i.e. new blocks and frames that were faulted back in
+            if self.compress_frames(&mut block, &mut index) > 0 {
+                info!("    Compressed block: {:?} to {} bytes with {:?}", block, index.stored_size(block.size()), self.algo);
+            } else if estimated {
+                self.estimator.wrongly_compressible += 1;
+            }
+            // Same-filled frames take no space, so only blocks with codec frames are worth promoting
+            if index.stats(block.size()).codec_original_bytes > 0 {
+                self.promote(&mut block, &mut index);
+            }
+            self.evict(&block, &mut index);
+            if index.is_compressed() && self.compressed.insert(block.ptr(), index).is_err() {
+                error!("    Too many compressed blocks to track {:?}", block);
+            }
+            block.protect();
         }
     }
 
-    pub fn decompress_allocation(&mut self, mut block: Block) {
+    /// Decompress the frames of `block` covering the page containing `addr`.
+    pub fn decompress_allocation(&mut self, mut block: Block, addr: *const u8) {
         let ptr = block.ptr();
-        if let Some(&compressed_size) = self.compressed_sizes.get(&ptr) {
-            if block.decompress(self.algo, compressed_size).is_some() {
-                info!("    Successfully decompressed block: {:?}", block);
-                self.compressed_sizes.remove(&ptr);
-            } else {
-                error!("    Could not decompress block: {:?}", block);
+        let Some(index) = self.compressed.get_mut(&ptr) else {
+            error!("    Could not find frame index for block: {:?}", block);
+            return;
+        };
+        let evicted = index.evicted_frames();
+        state::begin(&block, BlockState::Decompressing);
+        let start = Instant::now();
+        let decompressed = block.decompress_page(index, addr);
+        let elapsed = start.elapsed().as_nanos() as u64;
+        state::finish(&block, BlockState::settled(index.is_compressed()));
+        let read_backs = evicted - index.evicted_frames();
+        let fully_decompressed = !index.is_compressed();
+        if decompressed.is_some() {
+            info!("    Successfully decompressed page {:?} of block: {:?}", addr, block);
+            if fully_decompressed {
+                self.compressed.remove(&ptr);
             }
         } else {
-            error!("    Could not find compressed size for block: {:?}", block);
+            error!("    Could not decompress block: {:?}", block);
+        }
+        if read_backs > 0 {
+            debug!("    Read {} frames of block {:?} back from the far tier in {} ns", read_backs, block, elapsed);
+            self.count_far(&block, FarStats { read_backs, read_back_ns: elapsed, ..FarStats::default() });
         }
     }
 }
@@ -56,16 +580,41 @@ impl IntervalTest for CompressAlloc {
         Box::new(self.clone())
     }
 
-    fn on_access(&mut self, block: &Block, _is_write: bool) {
-        // Decompress the block
+    fn on_alloc(&mut self, alloc: &Block) {
+        self.aging.touch(alloc);
+    }
+
+    fn on_fault(&mut self, block: &Block, addr: *const u8, is_write: bool) {
+        self.aging.touch(block);
+        self.take_from_worker(block);
+        // New contents may well compress, so estimate the block again next interval
+        if is_write {
+            self.incompressible.remove(&block.ptr());
+        }
+        // Decompress the faulting page of the block
         if self.is_compressed(block) {
-            info!("Got access to block: {:?}, decompressing", block);
-            self.decompress_allocation(block.clone());
+            self.aging_stats.misses += 1;
+            self.aging_stats.interval_misses += 1;
+            info!("Got access to block: {:?} at {:?}, decompressing", block, addr);
+            self.decompress_allocation(*block, addr);
         }
     }
 
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        self.aging.forget(dealloc);
+        self.incompressible.remove(&dealloc.ptr());
+        self.take_from_worker(dealloc);
+        if let Some(mut index) = self.compressed.remove(&dealloc.ptr()) {
+            index.discard(&mut COMPRESSED_POOL.lock());
+        }
+        self.far.remove(&dealloc.ptr());
+        state::forget(dealloc);
+    }
+
     fn on_interval(&mut self) {
-        // Compress all allocations
+        // Compress the allocations that have gone cold
         self.compress_all_allocations();
+        self.report();
+        self.aging_stats.interval_misses = 0;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,269 @@
+use crate::{
+    compress::{open_payload, seal_payload, Compressor, PoolSlot, COMPRESSED_POOL},
+    globals::get_tracked_allocations,
+    mem::{align_down_to_page_size, align_up_to_page_size},
+    page_size,
+    state::{self, block_state, BlockState},
+    track::{Block, Track},
+    MAX_TRACKED_ALLOCATIONS,
+};
+use heapless::{FnvIndexMap as IndexMap, FnvIndexSet as IndexSet};
+use super::IntervalTest;
+use tracing::*;
+
+pub const MAX_DEDUP_PAGES: usize = 4096;
+
+/// One compressed copy of a page's contents, shared by every page collapsed into it.
+#[derive(Debug, Clone, Copy)]
+struct SharedPage {
+    slot: PoolSlot,
+    refs: usize,
+}
+
+/// Finds pages with identical contents across all tracked blocks.
+///
+/// Each interval, every whole page of every tracked block is hashed, and the
+/// duplicates and the bytes deduplication could save are reported. With
+/// `with_sharing`, identical pages that weren't accessed during the last interval
+/// are also collapsed, like KSM: one compressed copy is kept in the compressed
+/// pool, the duplicates are released, and a fault on one of them restores a
+/// private copy of the page. A block with collapsed pages is `Compressed`, so it
+/// stays protected across intervals and every access to it faults.
+///
+/// Sharing rewrites page contents, so it shouldn't be combined with `CompressAlloc`.
+#[derive(Clone)]
+pub struct DedupAnalysis {
+    sharing: Option<&'static dyn Compressor>,
+    /// Pages accessed since the last interval
+    accessed: IndexSet<usize, MAX_DEDUP_PAGES>,
+    /// The content hash of every collapsed page
+    collapsed: IndexMap<usize, u64, MAX_DEDUP_PAGES>,
+    shared: IndexMap<u64, SharedPage, MAX_DEDUP_PAGES>,
+}
+
+impl DedupAnalysis {
+    pub fn new() -> Self {
+        Self {
+            sharing: None,
+            accessed: IndexSet::new(),
+            collapsed: IndexMap::new(),
+            shared: IndexMap::new(),
+        }
+    }
+
+    /// Collapse identical cold pages into one copy compressed with `compressor`.
+    pub fn with_sharing(mut self, compressor: &'static dyn Compressor) -> Self {
+        compressor.preallocate();
+        self.sharing = Some(compressor);
+        self
+    }
+
+    /// The whole pages inside `block`, by address.
+    fn pages_of(block: &Block) -> impl Iterator<Item = usize> {
+        let page_size = page_size();
+        let start = align_up_to_page_size(block.ptr() as usize, page_size);
+        let end = align_down_to_page_size(block.ptr() as usize + block.size(), page_size);
+        (start..end.max(start)).step_by(page_size)
+    }
+
+    /// Copy `page`, a whole page of `block`, into `buf`. A block that isn't resident
+    /// is protected, so its pages are read through `Block::peek`.
+    fn read_page(block: &Block, page: usize, buf: &mut [u8]) -> bool {
+        let offset = page - block.ptr() as usize;
+        if block_state(block) == BlockState::Resident {
+            buf.copy_from_slice(&block.as_bytes()[offset..offset + buf.len()]);
+            true
+        } else {
+            block.peek(offset, buf)
+        }
+    }
+
+    /// Whether any page of `block` is collapsed.
+    fn has_collapsed(&self, block: &Block) -> bool {
+        Self::pages_of(block).any(|page| self.collapsed.contains_key(&page))
+    }
+
+    /// Hash every tracked page, returning how many pages have each hash.
+    fn hash_pages(&self, tracked: &Track<MAX_TRACKED_ALLOCATIONS>) -> IndexMap<u64, usize, MAX_DEDUP_PAGES> {
+        let mut counts = IndexMap::<u64, usize, MAX_DEDUP_PAGES>::new();
+        let mut bytes = std::vec![0u8; page_size()];
+        for block in tracked.iter() {
+            for page in Self::pages_of(block) {
+                let hash = match self.collapsed.get(&page) {
+                    Some(&hash) => hash,
+                    None if Self::read_page(block, page, &mut bytes) => xxhash_rust::xxh3::xxh3_64(&bytes),
+                    None => continue,
+                };
+                match counts.get_mut(&hash) {
+                    Some(count) => *count += 1,
+                    None => {
+                        if counts.insert(hash, 1).is_err() {
+                            warn!("    Too many distinct pages, dedup analysis is incomplete");
+                            return counts;
+                        }
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    fn report(&self, counts: &IndexMap<u64, usize, MAX_DEDUP_PAGES>) {
+        let total_pages: usize = counts.values().sum();
+        let duplicate_pages: usize = counts.values().filter(|&&count| count > 1).sum();
+        let saveable_pages: usize = counts.values().map(|&count| count - 1).sum();
+        info!("    {} tracked pages, {} distinct", total_pages, counts.len());
+        info!("    {} pages have a duplicate; deduplication could save {} bytes", duplicate_pages, saveable_pages * page_size());
+        if self.sharing.is_some() {
+            info!("    {} pages collapsed into {} shared copies", self.collapsed.len(), self.shared.len());
+        }
+    }
+
+    /// Collapse every cold page whose contents appear more than once.
+    fn collapse_duplicates(&mut self, compressor: &'static dyn Compressor, tracked: &Track<MAX_TRACKED_ALLOCATIONS>, counts: &IndexMap<u64, usize, MAX_DEDUP_PAGES>) {
+        // The first cold page seen with each duplicated hash, which the others are compared against
+        let mut originals = IndexMap::<u64, usize, MAX_DEDUP_PAGES>::new();
+        let mut pool = COMPRESSED_POOL.lock();
+        let mut bytes = std::vec![0u8; page_size()];
+        let mut other = std::vec![0u8; page_size()];
+
+        for block in tracked.iter() {
+            for page in Self::pages_of(block) {
+                if self.collapsed.contains_key(&page) || self.accessed.contains(&page) || !Self::read_page(block, page, &mut bytes) {
+                    continue;
+                }
+                let hash = xxhash_rust::xxh3::xxh3_64(&bytes);
+                if counts.get(&hash).copied().unwrap_or(0) < 2 {
+                    continue;
+                }
+
+                if let Some(shared) = self.shared.get(&hash) {
+                    // A copy already exists, make sure it really is the same page
+                    if open_payload(compressor, pool.get(&shared.slot), &mut other).is_err() || other != bytes {
+                        continue;
+                    }
+                } else {
+                    let Some(&original) = originals.get(&hash) else {
+                        let _ = originals.insert(hash, page);
+                        continue;
+                    };
+                    let Some(original_block) = tracked.get(original as *const u8) else { continue };
+                    if !Self::read_page(&original_block, original, &mut other) || other != bytes {
+                        continue;
+                    }
+                    // The second copy of this page: store it once, and collapse the original too
+                    let Some(payload) = seal_payload(compressor, &bytes) else { continue };
+                    let Some(slot) = pool.store(&payload) else { return };
+                    if self.shared.insert(hash, SharedPage { slot, refs: 0 }).is_err() {
+                        pool.free(slot);
+                        return;
+                    }
+                    if self.collapse(&original_block, original, hash) {
+                        self.shared[&hash].refs += 1;
+                    }
+                }
+
+                if self.collapse(block, page, hash) {
+                    self.shared[&hash].refs += 1;
+                }
+            }
+        }
+
+        // Shared copies that didn't end up with any pages
+        self.shared.retain(|_, shared| {
+            if shared.refs == 0 {
+                pool.free(shared.slot);
+            }
+            shared.refs > 0
+        });
+    }
+
+    /// Release `page` of `block`, remembering that it holds the shared copy for `hash`.
+    fn collapse(&mut self, block: &Block, page: usize, hash: u64) -> bool {
+        if self.collapsed.insert(page, hash).is_err() {
+            return false;
+        }
+        state::begin(block, BlockState::Compressing);
+        // Other threads may be running on the block's pages; from here on they fault and wait
+        block.protect();
+        let ret = unsafe { libc::madvise(page as *mut libc::c_void, page_size(), libc::MADV_DONTNEED) };
+        if ret != 0 {
+            error!("    Could not release page 0x{:x}", page);
+            self.collapsed.remove(&page);
+        }
+        state::finish(block, BlockState::settled(self.has_collapsed(block)));
+        ret == 0
+    }
+
+    /// Drop the reference of `page` of `block` to its shared copy, restoring its
+    /// contents if `restore` is set.
+    fn uncollapse(&mut self, block: &Block, page: usize, restore: bool) {
+        let Some(hash) = self.collapsed.remove(&page) else { return };
+        let still_collapsed = self.has_collapsed(block);
+        let Some(compressor) = self.sharing else { return };
+        let mut pool = COMPRESSED_POOL.lock();
+        let Some(shared) = self.shared.get_mut(&hash) else { return };
+
+        if restore {
+            state::begin(block, BlockState::Decompressing);
+            let mut bytes = std::vec![0u8; page_size()];
+            match open_payload(compressor, pool.get(&shared.slot), &mut bytes) {
+                // The block is still protected, so write through `/proc/self/mem`
+                Ok(()) if block.poke(page - block.ptr() as usize, &bytes) => {}
+                Ok(()) => error!("    Could not write back collapsed page 0x{:x}", page),
+                Err(error) => error!("    Could not restore collapsed page 0x{:x}: {}", page, error),
+            }
+            state::finish(block, BlockState::settled(still_collapsed));
+        }
+        shared.refs -= 1;
+        if shared.refs == 0 {
+            pool.free(shared.slot);
+            self.shared.remove(&hash);
+        }
+    }
+}
+
+impl Default for DedupAnalysis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for DedupAnalysis {
+    fn name(&self) -> &str {
+        "Page Deduplication Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_fault(&mut self, block: &Block, addr: *const u8, _is_write: bool) {
+        let page = Block::page_of(addr as *mut u8).ptr() as usize;
+        let _ = self.accessed.insert(page);
+        if self.collapsed.contains_key(&page) {
+            info!("Got access to collapsed page 0x{:x} of block {:?}, restoring", page, block);
+            self.uncollapse(block, page, true);
+        }
+    }
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        if !self.has_collapsed(dealloc) {
+            return;
+        }
+        for page in Self::pages_of(dealloc) {
+            self.uncollapse(dealloc, page, false);
+        }
+        state::forget(dealloc);
+    }
+
+    fn on_interval(&mut self) {
+        let tracked = get_tracked_allocations();
+        let counts = self.hash_pages(&tracked);
+        if let Some(compressor) = self.sharing {
+            self.collapse_duplicates(compressor, &tracked, &counts);
+        }
+        self.report(&counts);
+        self.accessed.clear();
+    }
+}
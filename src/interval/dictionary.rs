@@ -0,0 +1,306 @@
+use crate::{
+    compress::{fill_pattern, Compressor, ZstdDict, ZSTD, ZSTD_DICT},
+    globals::get_tracked_allocations,
+    site::{AllocationSite, SiteName},
+    state::{block_state, BlockState},
+    track::Block,
+    DICTIONARY_TRAINING_INTERVALS,
+};
+use heapless::FnvIndexMap as IndexMap;
+use super::{IntervalTest, MAX_SURVEY_SITES};
+use tracing::*;
+
+/// At most this many frames of each block are sampled per interval, so that large
+/// blocks don't crowd everything else out of the dictionary.
+pub const MAX_SAMPLED_FRAMES_PER_BLOCK: usize = 4;
+
+/// Sampling stops once this many bytes have been collected. If the dictionary
+/// can't be trained from that many, training is given up on.
+pub const MAX_DICTIONARY_SAMPLE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Once trained, the dictionary's gain is measured every this many intervals, on up
+/// to `MAX_SAMPLED_FRAMES_PER_BLOCK` frames of each block.
+pub const DICTIONARY_MEASURE_EVERY: u64 = 8;
+
+/// How much smaller frames got with the dictionary than without it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DictionaryGain {
+    pub original_bytes: usize,
+    /// Compressed without the dictionary
+    pub plain_bytes: usize,
+    /// Compressed against the dictionary
+    pub dictionary_bytes: usize,
+}
+
+impl DictionaryGain {
+    pub fn plain_ratio(&self) -> f64 {
+        self.original_bytes as f64 / self.plain_bytes.max(1) as f64
+    }
+
+    pub fn dictionary_ratio(&self) -> f64 {
+        self.original_bytes as f64 / self.dictionary_bytes.max(1) as f64
+    }
+
+    /// How many times better the ratio is with the dictionary.
+    pub fn improvement(&self) -> f64 {
+        self.plain_bytes as f64 / self.dictionary_bytes.max(1) as f64
+    }
+}
+
+impl core::ops::AddAssign for DictionaryGain {
+    fn add_assign(&mut self, other: Self) {
+        self.original_bytes += other.original_bytes;
+        self.plain_bytes += other.plain_bytes;
+        self.dictionary_bytes += other.dictionary_bytes;
+    }
+}
+
+/// Trains the `ZstdDict` codec's dictionary from frames sampled off the heap.
+///
+/// For the first `DICTIONARY_TRAINING_INTERVALS` intervals, up to
+/// `MAX_SAMPLED_FRAMES_PER_BLOCK` frames of every resident block are copied aside;
+/// same-filled frames are skipped, since they never reach the codec. The
+/// dictionary is then trained from the samples, retrying each interval with more
+/// samples if there weren't enough, until `MAX_DICTIONARY_SAMPLE_BYTES` are in hand.
+///
+/// Once trained, a sample of every resident block's frames is compressed with and
+/// without the dictionary every `DICTIONARY_MEASURE_EVERY` intervals, unless set
+/// with `with_measure_every`, and the gain is reported for each allocation site.
+/// `CompressAlloc::new(&compress::ZSTD_DICT)` compresses cold blocks against the
+/// dictionary; this test should run before it, so blocks are sampled before they're
+/// compressed.
+#[derive(Clone)]
+pub struct DictionaryTraining {
+    codec: &'static ZstdDict,
+    /// The same codec and level without a dictionary, to compare against
+    baseline: &'static dyn Compressor,
+    training_intervals: u64,
+    measure_every: u64,
+    interval: u64,
+    samples: std::vec::Vec<u8>,
+    sizes: std::vec::Vec<usize>,
+    /// Whether a frame didn't fit in the samples
+    samples_full: bool,
+    /// Set once training failed with no room left for more samples
+    given_up: bool,
+    sites: IndexMap<AllocationSite, DictionaryGain, MAX_SURVEY_SITES>,
+    total: DictionaryGain,
+}
+
+impl DictionaryTraining {
+    pub fn new() -> Self {
+        ZSTD_DICT.preallocate();
+        ZSTD.preallocate();
+        Self {
+            codec: &ZSTD_DICT,
+            baseline: &ZSTD,
+            training_intervals: DICTIONARY_TRAINING_INTERVALS,
+            measure_every: DICTIONARY_MEASURE_EVERY,
+            interval: 0,
+            samples: std::vec::Vec::new(),
+            sizes: std::vec::Vec::new(),
+            samples_full: false,
+            given_up: false,
+            sites: IndexMap::new(),
+            total: DictionaryGain::default(),
+        }
+    }
+
+    /// Train and measure `codec` instead of `ZSTD_DICT`.
+    pub fn with_codec(mut self, codec: &'static ZstdDict) -> Self {
+        codec.preallocate();
+        self.codec = codec;
+        self
+    }
+
+    /// Sample for `intervals` intervals before training.
+    pub fn with_training_intervals(mut self, intervals: u64) -> Self {
+        self.training_intervals = intervals;
+        self
+    }
+
+    /// Measure the dictionary's gain every `intervals` intervals once it's trained.
+    pub fn with_measure_every(mut self, intervals: u64) -> Self {
+        self.measure_every = intervals.max(1);
+        self
+    }
+
+    /// The gain for each allocation site, across all intervals since training.
+    pub fn sites(&self) -> &IndexMap<AllocationSite, DictionaryGain, MAX_SURVEY_SITES> {
+        &self.sites
+    }
+
+    pub fn total(&self) -> DictionaryGain {
+        self.total
+    }
+
+    /// The frames of `block` worth handing to the codec, i.e. those that aren't same-filled.
+    fn codec_frames<'a>(&self, block: &'a Block) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let index = block.frame_index(self.codec);
+        let data = block.as_bytes();
+        (0..index.frames().len())
+            .map(move |i| &data[index.frame_range(i, data.len())])
+            .filter(|frame| fill_pattern(frame).is_none())
+    }
+
+    fn sample(&mut self, block: &Block) {
+        for frame in self.codec_frames(block).take(MAX_SAMPLED_FRAMES_PER_BLOCK) {
+            if self.samples.len() + frame.len() > MAX_DICTIONARY_SAMPLE_BYTES {
+                self.samples_full = true;
+                return;
+            }
+            self.samples.extend_from_slice(frame);
+            self.sizes.push(frame.len());
+        }
+    }
+
+    fn train(&mut self) {
+        info!("    Training a Zstd dictionary from {} samples totalling {} bytes", self.sizes.len(), self.samples.len());
+        if let Some(id) = self.codec.train(&self.samples, &self.sizes) {
+            let size = self.codec.dictionary().map_or(0, |(_, size)| size);
+            info!("    Trained Zstd dictionary {} of {} bytes", id, size);
+        } else if self.samples_full {
+            warn!("    Giving up on training a Zstd dictionary, more samples won't fit");
+            self.given_up = true;
+        } else {
+            return;
+        }
+        self.samples = std::vec::Vec::new();
+        self.sizes = std::vec::Vec::new();
+    }
+
+    /// Compress a sample of the codec frames of `block` with and without the dictionary.
+    fn measure(&self, block: &Block) -> DictionaryGain {
+        let mut gain = DictionaryGain::default();
+        for frame in self.codec_frames(block).take(MAX_SAMPLED_FRAMES_PER_BLOCK) {
+            let (Some(plain), Some(dictionary)) = (self.baseline.compress(frame), self.codec.compress(frame)) else {
+                continue;
+            };
+            gain.original_bytes += frame.len();
+            gain.plain_bytes += plain.len();
+            gain.dictionary_bytes += dictionary.len();
+        }
+        gain
+    }
+
+    fn log_gain(label: impl core::fmt::Display, gain: &DictionaryGain) {
+        info!("    {}: {} bytes, ratio {:.2} without the dictionary, {:.2} with it ({:.2}x)",
+            label, gain.original_bytes, gain.plain_ratio(), gain.dictionary_ratio(), gain.improvement());
+    }
+
+    /// Sample `blocks` until the dictionary is trained, then measure its gain on them
+    /// every `measure_every` intervals.
+    fn sample_or_measure<'a>(&mut self, blocks: impl Iterator<Item = &'a Block>) {
+        if !self.codec.is_trained() {
+            for block in blocks {
+                self.sample(block);
+            }
+            if self.interval >= self.training_intervals {
+                self.train();
+            }
+            return;
+        }
+        if !self.interval.is_multiple_of(self.measure_every) {
+            return;
+        }
+
+        let mut interval = DictionaryGain::default();
+        for block in blocks {
+            let gain = self.measure(block);
+            if gain.original_bytes == 0 {
+                continue;
+            }
+            debug!("    Block {:?} from {}: ratio {:.2} without the dictionary, {:.2} with it",
+                block.ptr(), SiteName(block.site()), gain.plain_ratio(), gain.dictionary_ratio());
+            interval += gain;
+            self.total += gain;
+            match self.sites.get_mut(&block.site()) {
+                Some(site) => *site += gain,
+                None => {
+                    if self.sites.insert(block.site(), gain).is_err() {
+                        warn!("    Too many allocation sites to report, not recording {}", SiteName(block.site()));
+                    }
+                }
+            }
+        }
+        if interval.original_bytes > 0 {
+            Self::log_gain(format_args!("Interval #{}", self.interval), &interval);
+        }
+        self.report();
+    }
+
+    pub fn report(&self) {
+        info!("    Dictionary gain by allocation site, all intervals since training:");
+        for (site, gain) in self.sites.iter() {
+            Self::log_gain(SiteName(*site), gain);
+        }
+        Self::log_gain("All sites", &self.total);
+    }
+}
+
+impl Default for DictionaryTraining {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for DictionaryTraining {
+    fn name(&self) -> &str {
+        "Dictionary Training Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_interval(&mut self) {
+        self.interval += 1;
+        if self.given_up {
+            return;
+        }
+        let tracked = get_tracked_allocations();
+        // Blocks that aren't resident are protected, or their pages released
+        self.sample_or_measure(tracked.iter().filter(|block| block_state(block) == BlockState::Resident));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::test_records;
+
+    static CODEC: ZstdDict = ZstdDict::new(ZstdDict::DEFAULT_LEVEL);
+
+    #[test]
+    fn training_gives_up_when_full_and_gains_are_measured_every_few_intervals() {
+        let data = test_records(0, 64 * 4096);
+        let block = Block::new(data.as_ptr() as *mut u8, data.len());
+        let mut training = DictionaryTraining::new().with_codec(&CODEC).with_training_intervals(2);
+
+        training.interval = 1;
+        training.sample_or_measure(core::iter::once(&block));
+        assert_eq!(training.sizes.len(), MAX_SAMPLED_FRAMES_PER_BLOCK);
+        assert!(!training.samples_full && !CODEC.is_trained());
+
+        // Fill the samples up with zeroes, which no dictionary can be trained from
+        training.sizes.push(MAX_DICTIONARY_SAMPLE_BYTES - 1 - training.samples.len());
+        training.samples.resize(MAX_DICTIONARY_SAMPLE_BYTES - 1, 0);
+        training.interval = 2;
+        training.sample_or_measure(core::iter::once(&block));
+        assert!(training.samples_full && training.given_up);
+        assert!(!CODEC.is_trained());
+
+        let mut samples = std::vec::Vec::new();
+        for seed in (1000..200_000).step_by(1000) {
+            samples.extend_from_slice(&test_records(seed, 512));
+        }
+        assert!(CODEC.train(&samples, &[512; 199]).is_some());
+        let mut measuring = DictionaryTraining::new().with_codec(&CODEC).with_measure_every(3);
+        for interval in 1..=6 {
+            measuring.interval = interval;
+            let before = measuring.total().original_bytes;
+            measuring.sample_or_measure(core::iter::once(&block));
+            assert_eq!(measuring.total().original_bytes > before, interval % 3 == 0, "interval {interval}");
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use crate::{compress::CompressionAlgorithm, globals::get_tracked_allocations, track::Block};
+use crate::{compress::Compressor, globals::get_tracked_allocations};
 
 use super::IntervalTest;
 
 #[derive(Clone, Copy)]
-pub struct DummyCompressIntervalTest(pub CompressionAlgorithm);
+pub struct DummyCompressIntervalTest(pub &'static dyn Compressor);
 
 impl IntervalTest for DummyCompressIntervalTest {
     fn name(&self) -> &str {
@@ -18,10 +18,11 @@ impl IntervalTest for DummyCompressIntervalTest {
         let tracked = get_tracked_allocations();
         for mut block in tracked.into_iter() {
             tracing::info!("Found block: {block:?}");
-            if let Some(compressed_size) = block.compress(self.0) {
+            if let Some(mut index) = block.compress(self.0) {
+                let compressed_size = index.stored_size(block.size());
                 tracing::info!("Successfully compressed block: {block:?} to {compressed_size} bytes");
 
-                if block.decompress(self.0, compressed_size).is_some() {
+                if block.decompress(&mut index).is_some() {
                     tracing::info!("Successfully compressed and decompressed block: {block:?}");
                 } else {
                     tracing::error!("Could not decompress block: {block:?}");
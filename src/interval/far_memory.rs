@@ -0,0 +1,284 @@
+use crate::{
+    globals::get_tracked_allocations,
+    track::Block,
+    FAR_MEMORY_AGING_POLICY, FAR_MEMORY_LATENCY, FAR_MEMORY_PROMOTION_FAULTS, MAX_TRACKED_ALLOCATIONS,
+};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use heapless::FnvIndexMap as IndexMap;
+use std::time::{Duration, Instant};
+use super::{AccessAging, AgingPolicy, IntervalTest};
+use tracing::*;
+
+/// How an access fault on a far block is slowed down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FarLatency {
+    /// Busy-wait in the fault handler, for latencies too short to sleep accurately
+    Spin { ns: u64 },
+    /// Sleep in the fault handler; the kernel may oversleep by tens of microseconds
+    Sleep { ns: u64 },
+}
+
+impl FarLatency {
+    /// Stall the calling thread, returning how long it actually stalled for.
+    pub fn stall(&self) -> Duration {
+        let start = Instant::now();
+        match *self {
+            Self::Spin { ns } => {
+                let latency = Duration::from_nanos(ns);
+                while start.elapsed() < latency {
+                    core::hint::spin_loop();
+                }
+            }
+            Self::Sleep { ns } => std::thread::sleep(Duration::from_nanos(ns)),
+        }
+        start.elapsed()
+    }
+}
+
+std::thread_local! {
+    /// The stall owed by this thread's fault on a far block
+    static PENDING_STALL: Cell<Option<FarLatency>> = const { Cell::new(None) };
+}
+
+/// Time spent in `stall_pending` by every thread
+static STALLED_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Serve the stall owed by this thread's fault, if it was on a far block, returning
+/// how long it stalled for. The fault handler calls this after leaving the hook, so
+/// other threads' faults aren't held up behind the stall.
+pub fn stall_pending() -> Duration {
+    let Some(latency) = PENDING_STALL.try_with(|pending| pending.take()).ok().flatten() else {
+        return Duration::ZERO;
+    };
+    let stalled = latency.stall();
+    STALLED_NS.fetch_add(stalled.as_nanos() as u64, Ordering::Relaxed);
+    stalled
+}
+
+/// Demotions and promotions between near and far memory, and what far accesses cost.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FarMemoryStats {
+    /// Blocks moved to far memory after going cold
+    pub demotions: usize,
+    /// Far blocks moved back after turning hot
+    pub promotions: usize,
+    /// Faults on far blocks, each of which was charged the latency
+    pub far_faults: usize,
+    /// Faults on near blocks
+    pub near_faults: usize,
+    /// Time spent stalling far faults
+    pub emulated_ns: u64,
+}
+
+impl core::ops::AddAssign for FarMemoryStats {
+    fn add_assign(&mut self, other: Self) {
+        self.demotions += other.demotions;
+        self.promotions += other.promotions;
+        self.far_faults += other.far_faults;
+        self.near_faults += other.near_faults;
+        self.emulated_ns += other.emulated_ns;
+    }
+}
+
+/// Emulates a far memory tier, such as CXL-attached or remote memory, by charging
+/// a latency to every access fault on a block that lives in it.
+///
+/// Blocks start out near. At each interval, blocks that are cold under the
+/// `AgingPolicy` are demoted to far memory, `FAR_MEMORY_AGING_POLICY` unless set
+/// with `with_aging`. Every fault on a far block stalls the faulting thread for
+/// the `FarLatency`, once it's left the hook (see `stall_pending`); once a far block takes `FAR_MEMORY_PROMOTION_FAULTS` faults
+/// within one interval it has turned hot, and is promoted back to near memory.
+///
+/// Nothing is actually moved, so this only models the cost of a placement policy,
+/// reported as the slowdown over the run without the stalls.
+#[derive(Debug, Clone)]
+pub struct FarMemoryEmulation {
+    latency: FarLatency,
+    promotion_faults: usize,
+    aging: AccessAging,
+    /// The far blocks, with how many faults each has taken this interval
+    far: IndexMap<*const u8, usize, MAX_TRACKED_ALLOCATIONS>,
+    interval: FarMemoryStats,
+    total: FarMemoryStats,
+    start: Instant,
+    /// `STALLED_NS` when the stalls were last counted
+    stalled_ns: u64,
+}
+
+impl FarMemoryEmulation {
+    pub fn new() -> Self {
+        Self {
+            latency: FAR_MEMORY_LATENCY,
+            promotion_faults: FAR_MEMORY_PROMOTION_FAULTS,
+            aging: AccessAging::new(FAR_MEMORY_AGING_POLICY),
+            far: IndexMap::new(),
+            interval: FarMemoryStats::default(),
+            total: FarMemoryStats::default(),
+            start: Instant::now(),
+            stalled_ns: STALLED_NS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Charge `latency` to every fault on a far block.
+    pub fn with_latency(mut self, latency: FarLatency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Demote the blocks that are cold under `policy`.
+    pub fn with_aging(mut self, policy: AgingPolicy) -> Self {
+        self.aging = AccessAging::new(policy);
+        self
+    }
+
+    /// Promote a far block once it takes `faults` faults within one interval.
+    pub fn with_promotion_faults(mut self, faults: usize) -> Self {
+        self.promotion_faults = faults.max(1);
+        self
+    }
+
+    pub fn is_far(&self, block: &Block) -> bool {
+        self.far.contains_key(&block.ptr())
+    }
+
+    /// The stats of every interval so far, including the current one.
+    pub fn total(&self) -> FarMemoryStats {
+        let mut total = self.total;
+        total += self.interval;
+        total
+    }
+
+    /// Count the stalls served since they were last counted towards this interval.
+    fn count_stalls(&mut self) {
+        let stalled = STALLED_NS.load(Ordering::Relaxed);
+        self.interval.emulated_ns += stalled - self.stalled_ns;
+        self.stalled_ns = stalled;
+    }
+
+    /// End the interval, demoting whichever of `blocks` have gone cold.
+    fn demote<'a>(&mut self, blocks: impl Iterator<Item = &'a Block>) {
+        let cold = self.aging.sweep(blocks);
+        for &ptr in cold.iter() {
+            if self.far.contains_key(&ptr) {
+                continue;
+            }
+            if self.far.insert(ptr, 0).is_err() {
+                warn!("    Too many far blocks, keeping {:?} near", ptr);
+                continue;
+            }
+            self.interval.demotions += 1;
+        }
+        for faults in self.far.values_mut() {
+            *faults = 0;
+        }
+    }
+
+    fn report(&self, far_bytes: usize, near_bytes: usize) {
+        let total = self.total();
+        let elapsed = self.start.elapsed().as_nanos() as u64;
+        // The run would have taken this long without the stalls
+        let unstalled = elapsed.saturating_sub(total.emulated_ns).max(1);
+        info!("    {} blocks far ({} bytes), {} bytes near", self.far.len(), far_bytes, near_bytes);
+        info!("    This interval: {} demotions, {} promotions, {} far faults stalled for {:.3} ms, {} near faults",
+            self.interval.demotions, self.interval.promotions, self.interval.far_faults,
+            self.interval.emulated_ns as f64 / 1e6, self.interval.near_faults);
+        info!("    In total: {} demotions, {} promotions, {} far faults and {} near faults",
+            total.demotions, total.promotions, total.far_faults, total.near_faults);
+        info!("    Emulated {:?}: {:.3} ms of stalls over {:.3} ms, a {:.2}% slowdown",
+            self.latency, total.emulated_ns as f64 / 1e6, elapsed as f64 / 1e6, 100.0 * total.emulated_ns as f64 / unstalled as f64);
+    }
+}
+
+impl Default for FarMemoryEmulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for FarMemoryEmulation {
+    fn name(&self) -> &str {
+        "Far Memory Emulation Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, alloc: &Block) {
+        self.aging.touch(alloc);
+    }
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        self.aging.forget(dealloc);
+        self.far.remove(&dealloc.ptr());
+    }
+
+    fn on_fault(&mut self, block: &Block, addr: *const u8, _is_write: bool) {
+        self.aging.touch(block);
+        let Some(faults) = self.far.get_mut(&block.ptr()) else {
+            self.interval.near_faults += 1;
+            return;
+        };
+        *faults += 1;
+        let promote = *faults >= self.promotion_faults;
+        self.interval.far_faults += 1;
+        let _ = PENDING_STALL.try_with(|pending| pending.set(Some(self.latency)));
+        if promote {
+            trace!("Promoting {:?} after a fault at {:?}", block, addr);
+            self.far.remove(&block.ptr());
+            self.interval.promotions += 1;
+        }
+    }
+
+    fn on_interval(&mut self) {
+        self.count_stalls();
+        let tracked = get_tracked_allocations();
+        self.demote(tracked.iter());
+        let (far_bytes, near_bytes) = tracked.iter().fold((0, 0), |(far, near), block| {
+            if self.is_far(block) {
+                (far + block.size(), near)
+            } else {
+                (far, near + block.size())
+            }
+        });
+        self.report(far_bytes, near_bytes);
+        self.total += self.interval;
+        self.interval = FarMemoryStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cold_blocks_are_demoted_and_charged_until_promoted() {
+        let blocks = [Block::new(0x1000 as *mut u8, 4096), Block::new(0x3000 as *mut u8, 4096)];
+        let mut far = FarMemoryEmulation::new()
+            .with_latency(FarLatency::Spin { ns: 10_000 })
+            .with_aging(AgingPolicy::IdleIntervals(1))
+            .with_promotion_faults(2);
+        blocks.iter().for_each(|block| far.on_alloc(block));
+
+        far.demote(blocks.iter());
+        assert!(!far.is_far(&blocks[0]) && !far.is_far(&blocks[1]));
+        far.on_fault(&blocks[0], blocks[0].ptr(), false);
+        assert_eq!(stall_pending(), Duration::ZERO, "near faults aren't stalled");
+        far.demote(blocks.iter());
+        assert!(!far.is_far(&blocks[0]));
+        assert!(far.is_far(&blocks[1]));
+
+        far.on_fault(&blocks[1], blocks[1].ptr(), false);
+        assert!(far.is_far(&blocks[1]), "one fault shouldn't be enough to promote");
+        assert!(stall_pending() >= Duration::from_nanos(10_000));
+        far.on_fault(&blocks[1], blocks[1].ptr(), true);
+        assert!(!far.is_far(&blocks[1]));
+        stall_pending();
+        far.count_stalls();
+
+        let total = far.total();
+        assert_eq!((total.demotions, total.promotions, total.far_faults, total.near_faults), (1, 1, 2, 1));
+        assert!(total.emulated_ns >= 20_000);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{globals::get_tracked_allocations, track::Block, HEAT_POLICY, MAX_TRACKED_ALLOCATIONS};
+use heapless::FnvIndexMap as IndexMap;
+use spin::RwLock;
+use super::IntervalTest;
+use tracing::*;
+
+/// How often and how recently a block has been accessed, coldest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Heat {
+    /// Not accessed at all within the window
+    Frozen,
+    /// Accessed within the window, but not recently
+    Cold,
+    /// Accessed recently, but in fewer intervals than a hot block
+    Warm,
+    /// Accessed recently and in many intervals of the window
+    Hot,
+}
+
+impl Heat {
+    pub const ALL: [Heat; 4] = [Heat::Hot, Heat::Warm, Heat::Cold, Heat::Frozen];
+}
+
+/// How `HeatClassification` turns a block's access history into its `Heat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeatPolicy {
+    /// How many intervals of history are kept, at most 64
+    pub window: u32,
+    /// A block accessed within this many of the latest intervals is warm or hot
+    pub recent: u32,
+    /// A recently accessed block is hot once it's been accessed in this many
+    /// intervals of the window
+    pub hot_intervals: u32,
+}
+
+impl HeatPolicy {
+    fn mask(intervals: u32) -> u64 {
+        if intervals >= u64::BITS {
+            u64::MAX
+        } else {
+            (1 << intervals) - 1
+        }
+    }
+
+    /// Classify a history with a bit per interval, the latest in the lowest bit.
+    pub fn classify(&self, history: u64) -> Heat {
+        let history = history & Self::mask(self.window);
+        if history == 0 {
+            Heat::Frozen
+        } else if history & Self::mask(self.recent) == 0 {
+            Heat::Cold
+        } else if history.count_ones() >= self.hot_intervals {
+            Heat::Hot
+        } else {
+            Heat::Warm
+        }
+    }
+}
+
+/// The latest classification of every block, for other tests to query.
+static HEAT: RwLock<IndexMap<usize, Heat, MAX_TRACKED_ALLOCATIONS>> = RwLock::new(IndexMap::new());
+
+/// The heat of `block` as of the last interval `HeatClassification` ran, or `None`
+/// if it isn't running or hasn't seen the block at an interval yet.
+pub fn block_heat(block: &Block) -> Option<Heat> {
+    HEAT.read().get(&(block.ptr() as usize)).copied()
+}
+
+/// Bytes and blocks in each heat class at one interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeatBytes {
+    bytes: [usize; 4],
+    blocks: [usize; 4],
+}
+
+impl HeatBytes {
+    pub fn bytes(&self, heat: Heat) -> usize {
+        self.bytes[heat as usize]
+    }
+
+    pub fn blocks(&self, heat: Heat) -> usize {
+        self.blocks[heat as usize]
+    }
+
+    fn add(&mut self, heat: Heat, block: &Block) {
+        self.bytes[heat as usize] += block.size();
+        self.blocks[heat as usize] += 1;
+    }
+}
+
+/// Classifies every block as hot, warm, cold or frozen from a sliding window of
+/// its accesses, with a bit per interval set if the block faulted in it.
+///
+/// A block not accessed at all within the window is frozen, and one not accessed
+/// within the latest few intervals is cold. Of the recently accessed blocks, those
+/// accessed in enough intervals of the window are hot and the rest warm. The
+/// thresholds are the `HeatPolicy`, `HEAT_POLICY` unless set with `with_policy`.
+///
+/// Each interval the bytes in each class are reported, and the classification is
+/// published for other tests to query with `block_heat`, e.g. to compress with
+/// `AgingPolicy::Heat`. Tests added after this one see the current interval's.
+#[derive(Debug, Clone)]
+pub struct HeatClassification {
+    policy: HeatPolicy,
+    /// Each block's accesses, the current interval in the lowest bit
+    history: IndexMap<*const u8, u64, MAX_TRACKED_ALLOCATIONS>,
+    interval: u64,
+}
+
+impl HeatClassification {
+    pub fn new() -> Self {
+        Self {
+            policy: HEAT_POLICY,
+            history: IndexMap::new(),
+            interval: 0,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: HeatPolicy) -> Self {
+        assert!(policy.window <= u64::BITS, "Heat windows are at most {} intervals", u64::BITS);
+        self.policy = policy;
+        self
+    }
+
+    /// The heat of `block`, counting the current interval so far.
+    pub fn heat(&self, block: &Block) -> Heat {
+        self.policy.classify(self.history.get(&block.ptr()).copied().unwrap_or(0))
+    }
+
+    fn touch(&mut self, block: &Block) {
+        match self.history.get_mut(&block.ptr()) {
+            Some(history) => *history |= 1,
+            None => {
+                if self.history.insert(block.ptr(), 1).is_err() {
+                    warn!("Too many blocks to classify {:?}", block);
+                }
+            }
+        }
+    }
+
+    /// End the interval: classify `blocks`, publish the classification, and slide
+    /// the window along.
+    fn sweep<'a>(&mut self, blocks: impl Iterator<Item = &'a Block>) -> HeatBytes {
+        let mut classes = HeatBytes::default();
+        let mut published = HEAT.write();
+        published.clear();
+        for block in blocks {
+            let heat = self.heat(block);
+            classes.add(heat, block);
+            let _ = published.insert(block.ptr() as usize, heat);
+        }
+        let window = HeatPolicy::mask(self.policy.window);
+        for history in self.history.values_mut() {
+            *history = (*history << 1) & window;
+        }
+        self.interval += 1;
+        classes
+    }
+}
+
+impl Default for HeatClassification {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for HeatClassification {
+    fn name(&self) -> &str {
+        "Heat Classification Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, alloc: &Block) {
+        self.touch(alloc);
+    }
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        self.history.remove(&dealloc.ptr());
+        HEAT.write().remove(&(dealloc.ptr() as usize));
+    }
+
+    fn on_fault(&mut self, block: &Block, _addr: *const u8, _is_write: bool) {
+        self.touch(block);
+    }
+
+    fn on_interval(&mut self) {
+        let classes = self.sweep(get_tracked_allocations().iter());
+        info!("    Interval #{} heat over the last {} intervals:", self.interval, self.policy.window);
+        for heat in Heat::ALL {
+            info!("    {:?}: {} bytes in {} blocks", heat, classes.bytes(heat), classes.blocks(heat));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_cool_down_as_they_go_without_accesses() {
+        let policy = HeatPolicy { window: 4, recent: 1, hot_intervals: 2 };
+        let blocks = [Block::new(0x7100_0000 as *mut u8, 4096), Block::new(0x7100_2000 as *mut u8, 8192)];
+        let mut heat = HeatClassification::new().with_policy(policy);
+        blocks.iter().for_each(|block| heat.touch(block));
+
+        let classes = heat.sweep(blocks.iter());
+        assert_eq!(classes.bytes(Heat::Warm), 4096 + 8192);
+        heat.touch(&blocks[0]);
+        let classes = heat.sweep(blocks.iter());
+        assert_eq!((classes.blocks(Heat::Hot), classes.blocks(Heat::Cold)), (1, 1));
+        assert_eq!(block_heat(&blocks[0]), Some(Heat::Hot));
+        assert_eq!(block_heat(&blocks[1]), Some(Heat::Cold));
+
+        // Both accesses slide out of the window after four intervals
+        for _ in 0..3 {
+            heat.sweep(blocks.iter());
+        }
+        assert_eq!(heat.heat(&blocks[0]), Heat::Frozen);
+        assert_eq!(heat.heat(&blocks[1]), Heat::Frozen);
+    }
+}
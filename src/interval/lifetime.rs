@@ -0,0 +1,258 @@
+use crate::{
+    site::{AllocationSite, SiteName},
+    track::Block,
+    MAX_TRACKED_ALLOCATIONS, NEVER_FREED_INTERVALS, SHORT_LIVED_LIFETIME_US,
+};
+use heapless::FnvIndexMap as IndexMap;
+use std::time::Instant;
+use super::{IntervalTest, MAX_SURVEY_SITES};
+use tracing::*;
+
+/// Lifetime histogram buckets, a decade each.
+pub const LIFETIME_BUCKETS: [&str; 8] = ["<10us", "<100us", "<1ms", "<10ms", "<100ms", "<1s", "<10s", ">=10s"];
+
+/// Size classes by power of two, from blocks of a byte to blocks of 2^64 bytes.
+const SIZE_CLASSES: usize = usize::BITS as usize + 1;
+
+/// How long freed blocks lived.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LifetimeHistogram {
+    buckets: [u64; LIFETIME_BUCKETS.len()],
+    pub freed: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+    /// Interval boundaries crossed, summed over the freed blocks
+    pub total_intervals: u64,
+}
+
+impl LifetimeHistogram {
+    fn record(&mut self, ns: u64, intervals: u64) {
+        let us = ns / 1000;
+        let bucket = (0..LIFETIME_BUCKETS.len() - 1)
+            .find(|&i| us < 10u64.pow(i as u32 + 1))
+            .unwrap_or(LIFETIME_BUCKETS.len() - 1);
+        self.buckets[bucket] += 1;
+        self.freed += 1;
+        self.total_ns += ns;
+        self.max_ns = self.max_ns.max(ns);
+        self.total_intervals += intervals;
+    }
+
+    /// Freed blocks in each bucket of `LIFETIME_BUCKETS`.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn mean_us(&self) -> f64 {
+        self.total_ns as f64 / 1000.0 / self.freed.max(1) as f64
+    }
+
+    pub fn mean_intervals(&self) -> f64 {
+        self.total_intervals as f64 / self.freed.max(1) as f64
+    }
+}
+
+/// The lifetimes of one allocation site's blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SiteLifetimes {
+    pub lifetimes: LifetimeHistogram,
+    /// Blocks from the site that are still live
+    pub live: usize,
+    pub live_bytes: usize,
+    /// The interval the oldest live block was allocated in
+    pub oldest_live_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Birth {
+    at: Instant,
+    interval: u64,
+    site: AllocationSite,
+    size: usize,
+}
+
+/// Measures how long blocks live from allocation to free, in time and in intervals,
+/// by power-of-two size class and by allocation site.
+///
+/// Each interval the lifetime histograms of freed blocks are reported, and sites are
+/// flagged whose blocks are short-lived, living `SHORT_LIVED_LIFETIME_US` or less on
+/// average, or never freed, with none freed yet and live blocks at least
+/// `NEVER_FREED_INTERVALS` intervals old.
+#[derive(Clone)]
+pub struct LifetimeAnalysis {
+    interval: u64,
+    live: IndexMap<*const u8, Birth, MAX_TRACKED_ALLOCATIONS>,
+    size_classes: [LifetimeHistogram; SIZE_CLASSES],
+    sites: IndexMap<AllocationSite, LifetimeHistogram, MAX_SURVEY_SITES>,
+}
+
+impl LifetimeAnalysis {
+    pub fn new() -> Self {
+        Self {
+            interval: 0,
+            live: IndexMap::new(),
+            size_classes: [LifetimeHistogram::default(); SIZE_CLASSES],
+            sites: IndexMap::new(),
+        }
+    }
+
+    /// The size class of `size`: class `n` holds blocks of up to `2^n` bytes.
+    pub fn size_class(size: usize) -> usize {
+        (usize::BITS - size.saturating_sub(1).leading_zeros()) as usize
+    }
+
+    /// The lifetimes of freed blocks in size class `class`.
+    pub fn size_class_lifetimes(&self, class: usize) -> &LifetimeHistogram {
+        &self.size_classes[class]
+    }
+
+    /// The lifetimes of each site's blocks, freed and live.
+    pub fn sites(&self) -> IndexMap<AllocationSite, SiteLifetimes, MAX_SURVEY_SITES> {
+        let mut sites = IndexMap::<_, SiteLifetimes, MAX_SURVEY_SITES>::new();
+        for (&site, &lifetimes) in self.sites.iter() {
+            let _ = sites.insert(site, SiteLifetimes { lifetimes, ..Default::default() });
+        }
+        for birth in self.live.values() {
+            if !sites.contains_key(&birth.site) && sites.insert(birth.site, SiteLifetimes::default()).is_err() {
+                continue;
+            }
+            if let Some(site) = sites.get_mut(&birth.site) {
+                site.live += 1;
+                site.live_bytes += birth.size;
+                site.oldest_live_interval = Some(site.oldest_live_interval.map_or(birth.interval, |oldest| oldest.min(birth.interval)));
+            }
+        }
+        sites
+    }
+
+    /// Whether a site's blocks live `SHORT_LIVED_LIFETIME_US` or less on average.
+    pub fn is_short_lived(site: &SiteLifetimes) -> bool {
+        site.lifetimes.freed > 0 && site.lifetimes.mean_us() <= SHORT_LIVED_LIFETIME_US as f64
+    }
+
+    /// Whether none of a site's blocks have been freed, though some are at least
+    /// `NEVER_FREED_INTERVALS` intervals old.
+    pub fn is_never_freed(&self, site: &SiteLifetimes) -> bool {
+        site.lifetimes.freed == 0
+            && site.oldest_live_interval.is_some_and(|oldest| self.interval - oldest >= NEVER_FREED_INTERVALS)
+    }
+
+    fn free(&mut self, block: &Block) {
+        let Some(birth) = self.live.remove(&block.ptr()) else {
+            return;
+        };
+        let ns = birth.at.elapsed().as_nanos() as u64;
+        let intervals = self.interval - birth.interval;
+        self.size_classes[Self::size_class(birth.size)].record(ns, intervals);
+        match self.sites.get_mut(&birth.site) {
+            Some(site) => site.record(ns, intervals),
+            None => {
+                let mut site = LifetimeHistogram::default();
+                site.record(ns, intervals);
+                if self.sites.insert(birth.site, site).is_err() {
+                    warn!("Too many allocation sites to analyze, not recording {}", SiteName(birth.site));
+                }
+            }
+        }
+    }
+
+    fn log_histogram(label: impl core::fmt::Display, lifetimes: &LifetimeHistogram) {
+        let buckets = LIFETIME_BUCKETS.iter()
+            .zip(lifetimes.buckets())
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, count)| format!("{}: {}", bucket, count))
+            .collect::<std::vec::Vec<_>>()
+            .join(", ");
+        info!("        {}: {} freed, mean {:.1} us ({:.2} intervals), max {:.1} us [{}]",
+            label, lifetimes.freed, lifetimes.mean_us(), lifetimes.mean_intervals(), lifetimes.max_ns as f64 / 1000.0, buckets);
+    }
+
+    fn report(&self) {
+        info!("    Interval #{}: {} live blocks", self.interval, self.live.len());
+        info!("    Lifetimes by size class:");
+        for (class, lifetimes) in self.size_classes.iter().enumerate() {
+            if lifetimes.freed > 0 {
+                Self::log_histogram(format_args!("Up to {} bytes", 1u128 << class), lifetimes);
+            }
+        }
+        info!("    Lifetimes by allocation site:");
+        for (&site, lifetimes) in self.sites().iter() {
+            if lifetimes.lifetimes.freed > 0 {
+                Self::log_histogram(SiteName(site), &lifetimes.lifetimes);
+            }
+            if Self::is_short_lived(lifetimes) {
+                warn!("    Site {} is short-lived: {} blocks freed after {:.1} us on average",
+                    SiteName(site), lifetimes.lifetimes.freed, lifetimes.lifetimes.mean_us());
+            }
+            if self.is_never_freed(lifetimes) {
+                warn!("    Site {} never frees: {} blocks ({} bytes) live, the oldest from interval #{}",
+                    SiteName(site), lifetimes.live, lifetimes.live_bytes, lifetimes.oldest_live_interval.unwrap_or(0));
+            }
+        }
+    }
+}
+
+impl Default for LifetimeAnalysis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for LifetimeAnalysis {
+    fn name(&self) -> &str {
+        "Lifetime Analysis Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, alloc: &Block) {
+        let birth = Birth {
+            at: Instant::now(),
+            interval: self.interval,
+            site: alloc.site(),
+            size: alloc.size(),
+        };
+        if self.live.insert(alloc.ptr(), birth).is_err() {
+            warn!("Too many live blocks to analyze {:?}", alloc);
+        }
+    }
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        self.free(dealloc);
+    }
+
+    fn on_fault(&mut self, _block: &Block, _addr: *const u8, _is_write: bool) {}
+
+    fn on_interval(&mut self) {
+        self.interval += 1;
+        self.report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sites_are_flagged_short_lived_or_never_freed() {
+        let short = Block::new(0x1000 as *mut u8, 4096).with_site(0x10);
+        let leaked = Block::new(0x3000 as *mut u8, 8192).with_site(0x20);
+        let mut lifetimes = LifetimeAnalysis::new();
+        lifetimes.on_alloc(&short);
+        lifetimes.on_alloc(&leaked);
+        lifetimes.on_dealloc(&short);
+        for _ in 0..NEVER_FREED_INTERVALS {
+            lifetimes.on_interval();
+        }
+
+        assert_eq!(lifetimes.size_class_lifetimes(LifetimeAnalysis::size_class(4096)).freed, 1);
+        assert_eq!(LifetimeAnalysis::size_class(4097), 13);
+        let sites = lifetimes.sites();
+        let (short, leaked) = (sites[&0x10], sites[&0x20]);
+        assert!(LifetimeAnalysis::is_short_lived(&short) && !lifetimes.is_never_freed(&short));
+        assert!(!LifetimeAnalysis::is_short_lived(&leaked) && lifetimes.is_never_freed(&leaked));
+        assert_eq!((leaked.live, leaked.live_bytes), (1, 8192));
+    }
+}
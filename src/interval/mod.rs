@@ -1,7 +1,7 @@
-use std::time::Instant;
 use heapless::Vec;
 
 use crate::{
+    state::{block_state, BlockState},
     track::{Block, Permissions},
     globals::get_tracked_allocations
 };
@@ -12,9 +12,42 @@ pub use dummy::*;
 pub mod dummy_compress;
 pub use dummy_compress::*;
 
+pub mod aging;
+pub use aging::*;
+
 pub mod compress;
 pub use compress::*;
 
+pub mod trace;
+pub use trace::*;
+
+pub mod dedup;
+pub use dedup::*;
+
+pub mod survey;
+pub use survey::*;
+
+pub mod dictionary;
+pub use dictionary::*;
+
+pub mod far_memory;
+pub use far_memory::*;
+
+pub mod working_set;
+pub use working_set::*;
+
+pub mod heat;
+pub use heat::*;
+
+pub mod reuse;
+pub use reuse::*;
+
+pub mod lifetime;
+pub use lifetime::*;
+
+pub mod usage;
+pub use usage::*;
+
 pub trait IntervalTest {
     fn name(&self) -> &str;
 
@@ -31,7 +64,19 @@ pub trait IntervalTest {
         tracing::info!("Found dealloc: {:?}", dealloc);
     }
 
-    fn on_access(&mut self, block: &Block, is_write: bool) {
+    /// Called on an access fault, with the exact faulting address.
+    /// By default this forwards to `on_access` and then `on_write` or `on_read`.
+    fn on_fault(&mut self, block: &Block, addr: *const u8, is_write: bool) {
+        tracing::trace!("Fault at {:?} in block {:?}", addr, block);
+        self.on_access(block, is_write);
+        if is_write {
+            self.on_write(block);
+        } else {
+            self.on_read(block);
+        }
+    }
+
+    fn on_access(&mut self, block: &Block, _is_write: bool) {
         tracing::info!("Accessing block: {:?}", block);
     }
     fn on_write(&mut self, block: &Block) {
@@ -41,27 +86,104 @@ pub trait IntervalTest {
         tracing::info!("Reading from block: {:?}", block);
     }
 
+    /// Called for every single-stepped access when `SINGLE_STEP_TRACING` is enabled,
+    /// with the exact faulting address and the program counter of the instruction.
+    fn on_trace(&mut self, block: &Block, addr: *const u8, pc: usize, is_write: bool) {
+        tracing::trace!("Traced access to {:?} at {:?} from pc 0x{:x} (write: {})", block, addr, pc, is_write);
+    }
+
     fn on_interval(&mut self) {
         tracing::info!("Interval test: {}", self.name());
     }
+
+    /// The clock this test's intervals are measured in, or `None` to use the suite's.
+    fn clock(&self) -> Option<IntervalClock> {
+        None
+    }
 }
 
 pub const MAX_INTERVAL_TESTS: usize = 100;
 
+/// What an interval is measured in.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum IntervalClock {
+    /// Every N milliseconds of wall-clock time
+    WallTime { ms: u64 },
+    /// Every N milliseconds of CPU time used by the whole process (`CLOCK_PROCESS_CPUTIME_ID`)
+    CpuTime { ms: u64 },
+    /// Every N tracked allocations
+    Allocations(u64),
+    /// Every N bytes of tracked allocations
+    BytesAllocated(u64),
+    /// Every N access faults on tracked allocations
+    Faults(u64),
+}
+
+impl IntervalClock {
+    /// How far this clock has advanced between two readings.
+    pub fn elapsed(&self, since: &IntervalClockReading, now: &IntervalClockReading) -> u64 {
+        match self {
+            Self::WallTime { .. } => now.wall_ms - since.wall_ms,
+            Self::CpuTime { .. } => now.cpu_ms - since.cpu_ms,
+            Self::Allocations(_) => now.allocations - since.allocations,
+            Self::BytesAllocated(_) => now.bytes_allocated - since.bytes_allocated,
+            Self::Faults(_) => now.faults - since.faults,
+        }
+    }
+
+    /// The length of one interval, in this clock's unit.
+    pub fn period(&self) -> u64 {
+        match *self {
+            Self::WallTime { ms } | Self::CpuTime { ms } => ms,
+            Self::Allocations(n) | Self::BytesAllocated(n) | Self::Faults(n) => n,
+        }
+    }
+
+    pub fn is_ready(&self, since: &IntervalClockReading, now: &IntervalClockReading) -> bool {
+        self.elapsed(since, now) >= self.period()
+    }
+}
+
+/// A snapshot of every interval clock at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IntervalClockReading {
+    pub wall_ms: u64,
+    pub cpu_ms: u64,
+    pub allocations: u64,
+    pub bytes_allocated: u64,
+    pub faults: u64,
+}
+
+fn clock_ms(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { core::mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub struct IntervalTestConfig {
-    pub interval_ms: u64,
+    /// The clock used by every test that doesn't pick its own with `IntervalTest::clock`
+    pub clock: IntervalClock,
 }
 
 pub struct IntervalTestSuite {
-    last_interval: Instant,
+    counters: IntervalClockReading,
     total_intervals_executed: u64,
     tests: Vec<Box<dyn IntervalTest>, MAX_INTERVAL_TESTS>,
+    /// When each test last ran, or `None` if it hasn't run yet
+    last_intervals: Vec<Option<IntervalClockReading>, MAX_INTERVAL_TESTS>,
 }
 
 impl IntervalTestSuite {
     pub const fn new() -> Self {
-        Self { tests: Vec::new(), last_interval: unsafe {core::mem::zeroed()}, total_intervals_executed: 0 }
+        Self {
+            counters: IntervalClockReading { wall_ms: 0, cpu_ms: 0, allocations: 0, bytes_allocated: 0, faults: 0 },
+            total_intervals_executed: 0,
+            tests: Vec::new(),
+            last_intervals: Vec::new(),
+        }
     }
 
     pub fn from_tests(tests: &[Box<dyn IntervalTest>]) -> Self {
@@ -74,26 +196,47 @@ impl IntervalTestSuite {
 
     pub fn add_test(&mut self, test: &dyn IntervalTest) {
         self.tests.push(test.boxed()).map_err(|_| ()).expect("Failed to add test");
+        self.last_intervals.push(None).expect("Failed to add test");
+    }
+
+    /// How many intervals have run so far.
+    pub fn intervals_executed(&self) -> u64 {
+        self.total_intervals_executed
+    }
+
+    /// Read every interval clock.
+    pub fn read_clocks(&self) -> IntervalClockReading {
+        IntervalClockReading {
+            wall_ms: clock_ms(libc::CLOCK_MONOTONIC),
+            cpu_ms: clock_ms(libc::CLOCK_PROCESS_CPUTIME_ID),
+            ..self.counters
+        }
     }
 
-    fn is_ready(&self, config: &IntervalTestConfig) -> bool {
-        if self.total_intervals_executed == 0 {
-            return true;
+    fn is_ready(&self, i: usize, config: &IntervalTestConfig, now: &IntervalClockReading) -> bool {
+        let clock = self.tests[i].clock().unwrap_or(config.clock);
+        match &self.last_intervals[i] {
+            Some(since) => clock.is_ready(since, now),
+            None => true,
         }
-        let elapsed = self.last_interval.elapsed().as_millis();
-        elapsed >= config.interval_ms as u128
     }
 
     pub fn schedule(&mut self, config: &IntervalTestConfig) {
-        if self.is_ready(config) {
+        let now = self.read_clocks();
+        let ready = (0..self.tests.len())
+            .filter(|&i| self.is_ready(i, config, &now))
+            .collect::<Vec<usize, MAX_INTERVAL_TESTS>>();
+
+        if !ready.is_empty() {
             self.total_intervals_executed += 1;
             tracing::info!("Running tests for interval #{}", self.total_intervals_executed);
             let mut to_remove = Vec::<usize, MAX_INTERVAL_TESTS>::new();
 
             self.unprotect_allocations();
-            
-            self.last_interval = Instant::now();
-            for (i, test) in self.tests.iter_mut().enumerate() {
+
+            for &i in ready.iter() {
+                let test = &mut self.tests[i];
+                self.last_intervals[i] = Some(now);
                 tracing::info!("Running test: {}", test.name());
                 test.on_interval();
 
@@ -104,8 +247,10 @@ impl IntervalTestSuite {
                 }
             }
 
-            for i in to_remove.iter() {
-                self.tests.remove(*i);
+            // Remove from the back so the remaining indices stay valid
+            for &i in to_remove.iter().rev() {
+                self.tests.remove(i);
+                self.last_intervals.remove(i);
             }
 
             tracing::info!("Interval #{} complete", self.total_intervals_executed);
@@ -122,14 +267,20 @@ impl IntervalTestSuite {
     }
 
     fn unprotect_allocations(&self) {
-        tracing::trace!("Unprotecting all allocations");
+        tracing::trace!("Unprotecting all resident allocations");
         let blocks = get_tracked_allocations();
         for block in blocks.iter() {
-            block.change_permissions(Permissions::READ | Permissions::WRITE);
+            unprotect_if_resident(block);
         }
     }
 }
 
+impl Default for IntervalTestSuite {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl IntervalTest for IntervalTestSuite {
     fn name(&self) -> &str {
         "IntervalTestSuite"
@@ -140,7 +291,9 @@ impl IntervalTest for IntervalTestSuite {
     }
 
     fn on_alloc(&mut self, alloc: &Block) {
-        alloc.unprotect();
+        self.counters.allocations += 1;
+        self.counters.bytes_allocated += alloc.size() as u64;
+        unprotect_if_resident(alloc);
         for test in self.tests.iter_mut() {
             test.on_alloc(alloc);
         }
@@ -148,15 +301,24 @@ impl IntervalTest for IntervalTestSuite {
     }
 
     fn on_dealloc(&mut self, dealloc: &Block) {
-        dealloc.unprotect();
+        unprotect_if_resident(dealloc);
         for test in self.tests.iter_mut() {
             test.on_dealloc(dealloc);
         }
         dealloc.protect();
     }
 
+    fn on_fault(&mut self, block: &Block, addr: *const u8, is_write: bool) {
+        self.counters.faults += 1;
+        unprotect_if_resident(block);
+        for test in self.tests.iter_mut() {
+            test.on_fault(block, addr, is_write);
+        }
+        block.protect();
+    }
+
     fn on_access(&mut self, block: &Block, is_write: bool) {
-        block.unprotect();
+        unprotect_if_resident(block);
         for test in self.tests.iter_mut() {
             test.on_access(block, is_write);
             if is_write {
@@ -169,7 +331,7 @@ impl IntervalTest for IntervalTestSuite {
     }
 
     fn on_write(&mut self, block: &Block) {
-        block.unprotect();
+        unprotect_if_resident(block);
         for test in self.tests.iter_mut() {
             test.on_write(block);
         }
@@ -177,12 +339,27 @@ impl IntervalTest for IntervalTestSuite {
     }
 
     fn on_read(&mut self, block: &Block) {
-        block.unprotect();
+        unprotect_if_resident(block);
         for test in self.tests.iter_mut() {
             test.on_read(block);
         }
         block.protect();
     }
+
+    fn on_trace(&mut self, block: &Block, addr: *const u8, pc: usize, is_write: bool) {
+        for test in self.tests.iter_mut() {
+            test.on_trace(block, addr, pc, is_write);
+        }
+    }
+}
+
+/// Unprotect `block` for the tests, unless any of it is (being) compressed. Other
+/// threads keep running meanwhile, so such a block stays protected for them to
+/// fault on and wait, rather than read pages that were released.
+fn unprotect_if_resident(block: &Block) {
+    if block_state(block) == BlockState::Resident {
+        block.unprotect();
+    }
 }
 
 unsafe impl Send for IntervalTestSuite {}
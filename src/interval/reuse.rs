@@ -0,0 +1,174 @@
+use crate::{page_size, track::Block};
+use heapless::Vec;
+use super::IntervalTest;
+use tracing::*;
+
+/// How many distinct pages the LRU stack holds; re-references further down than
+/// this count as misses at every modelled size.
+pub const MAX_REUSE_DISTANCE_PAGES: usize = 4096;
+
+/// Histogram buckets: distance 0, then one per power of two up to the stack size.
+const REUSE_DISTANCE_BUCKETS: usize = MAX_REUSE_DISTANCE_PAGES.trailing_zeros() as usize + 1;
+
+/// Reuse distances of page references, bucketed by powers of two.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReuseHistogram {
+    /// Bucket 0 counts distance 0, and bucket `i` distances in `2^(i-1)..2^i`
+    buckets: [u64; REUSE_DISTANCE_BUCKETS],
+    /// First references, and re-references too far back to measure
+    infinite: u64,
+}
+
+impl ReuseHistogram {
+    fn bucket(distance: usize) -> usize {
+        (usize::BITS - distance.leading_zeros()) as usize
+    }
+
+    pub fn record(&mut self, distance: Option<usize>) {
+        match distance {
+            Some(distance) => self.buckets[Self::bucket(distance)] += 1,
+            None => self.infinite += 1,
+        }
+    }
+
+    pub fn references(&self) -> u64 {
+        self.buckets.iter().sum::<u64>() + self.infinite
+    }
+
+    pub fn infinite(&self) -> u64 {
+        self.infinite
+    }
+
+    /// How many of the references would have faulted with only `pages` pages kept
+    /// resident, under LRU. Exact for powers of two up to `MAX_REUSE_DISTANCE_PAGES`.
+    pub fn misses(&self, pages: usize) -> u64 {
+        // A reference at distance `d` hits once more than `d` pages fit
+        let first_miss = if pages == 0 { 0 } else { Self::bucket(pages - 1) + 1 };
+        self.buckets.iter().skip(first_miss).sum::<u64>() + self.infinite
+    }
+
+    pub fn miss_ratio(&self, pages: usize) -> f64 {
+        self.misses(pages) as f64 / self.references().max(1) as f64
+    }
+}
+
+/// Computes the reuse distance of page references, and from it a miss-ratio curve:
+/// how many faults there would have been if only so much of the tracked memory
+/// were kept resident, uncompressed or in a fast tier.
+///
+/// Every fault re-protects all blocks, so the faults are the sequence of pages
+/// touched, minus repeated touches of the same page. Each fault is looked up in an
+/// LRU stack of the last `MAX_REUSE_DISTANCE_PAGES` distinct pages; its distance
+/// is how many other pages were touched since the page's last reference.
+///
+/// The curve is reported at every power-of-two size up to the stack size. Pages
+/// of freed blocks are dropped from the stack.
+#[derive(Clone)]
+pub struct ReuseDistance {
+    /// Distinct pages, the most recently referenced last
+    stack: Vec<usize, MAX_REUSE_DISTANCE_PAGES>,
+    histogram: ReuseHistogram,
+    interval_references: u64,
+    interval: u64,
+}
+
+impl ReuseDistance {
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            histogram: ReuseHistogram::default(),
+            interval_references: 0,
+            interval: 0,
+        }
+    }
+
+    pub fn histogram(&self) -> &ReuseHistogram {
+        &self.histogram
+    }
+
+    /// Reference the page at `page`, returning its reuse distance, or `None` if it
+    /// isn't in the stack.
+    fn reference(&mut self, page: usize) -> Option<usize> {
+        let distance = match self.stack.iter().rposition(|&p| p == page) {
+            Some(i) => {
+                self.stack.remove(i);
+                Some(self.stack.len() - i)
+            }
+            None => {
+                if self.stack.is_full() {
+                    self.stack.remove(0);
+                }
+                None
+            }
+        };
+        let _ = self.stack.push(page);
+        self.histogram.record(distance);
+        self.interval_references += 1;
+        distance
+    }
+
+    fn report(&self) {
+        let histogram = &self.histogram;
+        info!("    Interval #{}: {} page references, {} in total, {} first or beyond {} pages",
+            self.interval, self.interval_references, histogram.references(), histogram.infinite(), MAX_REUSE_DISTANCE_PAGES);
+        info!("    Miss-ratio curve, faults with only so much memory kept resident:");
+        for bucket in 0..REUSE_DISTANCE_BUCKETS {
+            let pages = 1 << bucket;
+            info!("        {:>8} KiB: {} faults (miss ratio {:.3})",
+                pages * page_size() / 1024, histogram.misses(pages), histogram.miss_ratio(pages));
+        }
+    }
+}
+
+impl Default for ReuseDistance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for ReuseDistance {
+    fn name(&self) -> &str {
+        "Reuse Distance Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, _alloc: &Block) {}
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        let start = Block::page_of(dealloc.ptr_mut()).ptr() as usize;
+        let end = dealloc.ptr() as usize + dealloc.size();
+        self.stack.retain(|&page| page < start || page >= end);
+    }
+
+    fn on_fault(&mut self, _block: &Block, addr: *const u8, _is_write: bool) {
+        self.reference(Block::page_of(addr as *mut u8).ptr() as usize);
+    }
+
+    fn on_interval(&mut self) {
+        self.interval += 1;
+        self.report();
+        self.interval_references = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances_count_distinct_pages_in_between() {
+        let mut reuse = ReuseDistance::new();
+        for page in [1, 2, 3, 2, 2, 1, 3] {
+            reuse.reference(page);
+        }
+        // 1, 2 and 3 are first references; then 2 at 1, 2 at 0, 1 at 2 and 3 at 2
+        let histogram = reuse.histogram();
+        assert_eq!((histogram.references(), histogram.infinite()), (7, 3));
+        assert_eq!(histogram.misses(1), 3 + 3);
+        assert_eq!(histogram.misses(2), 3 + 2);
+        assert_eq!(histogram.misses(4), 3);
+    }
+}
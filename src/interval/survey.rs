@@ -0,0 +1,232 @@
+use std::time::Instant;
+
+use crate::{
+    compress::{compressors, Compressor, MAX_COMPRESSORS},
+    globals::get_tracked_allocations,
+    site::{AllocationSite, SiteName},
+    state::{block_state, BlockState},
+    track::Block,
+};
+use core::fmt::Write as _;
+use heapless::{FnvIndexMap as IndexMap, String, Vec};
+use super::IntervalTest;
+use tracing::*;
+
+pub const MAX_SURVEY_SITES: usize = 64;
+
+/// How one codec did on some amount of data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CodecStats {
+    pub original_bytes: usize,
+    pub compressed_bytes: usize,
+    pub compress_ns: u64,
+    pub decompress_ns: u64,
+    /// Frames that failed to compress, or didn't decompress to the original bytes
+    pub failures: usize,
+}
+
+impl CodecStats {
+    pub fn ratio(&self) -> f64 {
+        self.original_bytes as f64 / self.compressed_bytes.max(1) as f64
+    }
+
+    /// Compression throughput, in MB/s of original data.
+    pub fn compress_mbps(&self) -> f64 {
+        self.original_bytes as f64 * 1000.0 / self.compress_ns.max(1) as f64
+    }
+
+    /// Decompression throughput, in MB/s of original data.
+    pub fn decompress_mbps(&self) -> f64 {
+        self.original_bytes as f64 * 1000.0 / self.decompress_ns.max(1) as f64
+    }
+}
+
+impl core::ops::AddAssign for CodecStats {
+    fn add_assign(&mut self, other: Self) {
+        self.original_bytes += other.original_bytes;
+        self.compressed_bytes += other.compressed_bytes;
+        self.compress_ns += other.compress_ns;
+        self.decompress_ns += other.decompress_ns;
+        self.failures += other.failures;
+    }
+}
+
+/// One `CodecStats` per surveyed codec, in the order of `CodecSurvey::codecs`.
+pub type CodecTable = Vec<CodecStats, MAX_COMPRESSORS>;
+
+/// Compares every registered codec and level on the real heap, without changing it.
+///
+/// Each interval, every resident block is compressed one frame at a time with each
+/// codec into a scratch buffer, and decompressed again to check the round trip.
+/// The ratio and compression and decompression times are logged per block, and a
+/// table comparing the codecs is printed for the interval, for each allocation
+/// site, and for the whole run.
+///
+/// Blocks are only read. Blocks another test has compressed stay protected, and
+/// are skipped until they're resident again.
+#[derive(Clone)]
+pub struct CodecSurvey {
+    codecs: Vec<&'static dyn Compressor, MAX_COMPRESSORS>,
+    interval: u64,
+    sites: IndexMap<AllocationSite, CodecTable, MAX_SURVEY_SITES>,
+    total: CodecTable,
+}
+
+impl CodecSurvey {
+    /// Survey every codec in the registry.
+    pub fn new() -> Self {
+        Self::with_codecs(&compressors())
+    }
+
+    /// Survey only the given codecs.
+    pub fn with_codecs(codecs: &[&'static dyn Compressor]) -> Self {
+        let codecs = Vec::from_slice(codecs).unwrap_or_default();
+        let empty = Self::empty_table(codecs.len());
+        Self {
+            codecs,
+            interval: 0,
+            sites: IndexMap::new(),
+            total: empty,
+        }
+    }
+
+    fn empty_table(len: usize) -> CodecTable {
+        let mut table = CodecTable::new();
+        table.resize_default(len).ok();
+        table
+    }
+
+    pub fn codecs(&self) -> &[&'static dyn Compressor] {
+        &self.codecs
+    }
+
+    /// The results for each allocation site, across all intervals so far.
+    pub fn sites(&self) -> &IndexMap<AllocationSite, CodecTable, MAX_SURVEY_SITES> {
+        &self.sites
+    }
+
+    /// The results across all blocks and intervals so far.
+    pub fn total(&self) -> &CodecTable {
+        &self.total
+    }
+
+    /// Compress and decompress `block` one frame at a time with `codec`.
+    fn survey(codec: &'static dyn Compressor, block: &Block) -> CodecStats {
+        let mut stats = CodecStats::default();
+        let index = block.frame_index(codec);
+        let data = block.as_bytes();
+        for i in 0..index.frames().len() {
+            let frame = &data[index.frame_range(i, data.len())];
+
+            let start = Instant::now();
+            let compressed = codec.compress(frame);
+            stats.compress_ns += start.elapsed().as_nanos() as u64;
+            let Some(compressed) = compressed else {
+                stats.failures += 1;
+                continue;
+            };
+
+            let start = Instant::now();
+            let decompressed = codec.decompress(&compressed, frame.len());
+            stats.decompress_ns += start.elapsed().as_nanos() as u64;
+            if decompressed.is_none_or(|decompressed| decompressed[..] != *frame) {
+                stats.failures += 1;
+                continue;
+            }
+
+            stats.original_bytes += frame.len();
+            stats.compressed_bytes += compressed.len();
+        }
+        stats
+    }
+
+    fn log_table<'a>(&self, rows: impl Iterator<Item = (&'static dyn Compressor, &'a CodecStats)>) {
+        info!("    {:<12} {:>12} {:>12} {:>8} {:>14} {:>16} {:>8}", "codec", "bytes", "compressed", "ratio", "compress MB/s", "decompress MB/s", "failed");
+        for (codec, stats) in rows {
+            // `Debug for dyn Compressor` ignores padding, so format the name first
+            let mut name = String::<32>::new();
+            let _ = write!(name, "{:?}", codec);
+            info!(
+                "    {:<12} {:>12} {:>12} {:>8.2} {:>14.1} {:>16.1} {:>8}",
+                name, stats.original_bytes, stats.compressed_bytes, stats.ratio(),
+                stats.compress_mbps(), stats.decompress_mbps(), stats.failures
+            );
+        }
+    }
+
+    /// The codec with the best ratio in `table`.
+    fn best<'a>(&'a self, table: &'a CodecTable) -> Option<(&'static dyn Compressor, &'a CodecStats)> {
+        self.codecs
+            .iter()
+            .copied()
+            .zip(table.iter())
+            .max_by(|(_, a), (_, b)| a.ratio().total_cmp(&b.ratio()))
+    }
+
+    fn log_ratios(&self, table: &CodecTable) {
+        for (codec, stats) in self.codecs.iter().zip(table.iter()) {
+            debug!("        {:?}: ratio {:.2}, {:.1} MB/s compress, {:.1} MB/s decompress",
+                codec, stats.ratio(), stats.compress_mbps(), stats.decompress_mbps());
+        }
+    }
+}
+
+impl Default for CodecSurvey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for CodecSurvey {
+    fn name(&self) -> &str {
+        "Codec Survey Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_interval(&mut self) {
+        self.interval += 1;
+        let mut interval = Self::empty_table(self.codecs.len());
+
+        let tracked = get_tracked_allocations();
+        // Blocks that aren't resident are protected, or their pages released
+        for block in tracked.iter().filter(|block| block_state(block) == BlockState::Resident) {
+            let mut row = Self::empty_table(self.codecs.len());
+            for (codec, stats) in self.codecs.iter().zip(row.iter_mut()) {
+                *stats = Self::survey(*codec, block);
+            }
+            if let Some((codec, stats)) = self.best(&row) {
+                debug!("    Block {:?} from {}: {} bytes, best ratio {:.2} with {:?}",
+                    block.ptr(), SiteName(block.site()), block.size(), stats.ratio(), codec);
+            }
+            self.log_ratios(&row);
+
+            if !self.sites.contains_key(&block.site())
+                && self.sites.insert(block.site(), Self::empty_table(self.codecs.len())).is_err()
+            {
+                warn!("    Too many allocation sites to survey, not recording {}", SiteName(block.site()));
+            }
+            for (i, stats) in row.iter().enumerate() {
+                interval[i] += *stats;
+                self.total[i] += *stats;
+                if let Some(site) = self.sites.get_mut(&block.site()) {
+                    site[i] += *stats;
+                }
+            }
+        }
+
+        info!("    Codec survey for interval #{}:", self.interval);
+        self.log_table(self.codecs.iter().copied().zip(interval.iter()));
+        info!("    By allocation site, all intervals:");
+        for (site, table) in self.sites.iter() {
+            if let Some((codec, stats)) = self.best(table) {
+                info!("    {}: {} bytes, best ratio {:.2} with {:?}", SiteName(*site), stats.original_bytes, stats.ratio(), codec);
+            }
+            self.log_ratios(table);
+        }
+        info!("    All intervals:");
+        self.log_table(self.codecs.iter().copied().zip(self.total.iter()));
+    }
+}
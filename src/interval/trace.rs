@@ -0,0 +1,107 @@
+use crate::track::Block;
+use heapless::Vec;
+use super::IntervalTest;
+use tracing::*;
+
+pub const MAX_TRACE_RECORDS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRecord {
+    pub interval: u64,
+    pub block: *const u8,
+    pub addr: *const u8,
+    pub pc: usize,
+    pub is_write: bool,
+}
+
+/// Records every single-stepped access to the selected blocks.
+///
+/// Only receives events when `SINGLE_STEP_TRACING` is enabled; the trace is
+/// printed and cleared at the end of each interval.
+#[derive(Clone)]
+pub struct AccessTrace {
+    min_size: usize,
+    max_size: usize,
+    interval: u64,
+    dropped: u64,
+    records: Vec<AccessRecord, MAX_TRACE_RECORDS>,
+}
+
+impl AccessTrace {
+    pub fn new() -> Self {
+        Self {
+            min_size: 0,
+            max_size: usize::MAX,
+            interval: 0,
+            dropped: 0,
+            records: Vec::new(),
+        }
+    }
+
+    /// Only trace blocks whose size lies within `min..=max` bytes.
+    pub fn with_size_range(mut self, min: usize, max: usize) -> Self {
+        self.min_size = min;
+        self.max_size = max;
+        self
+    }
+
+    pub fn is_selected(&self, block: &Block) -> bool {
+        block.size() >= self.min_size && block.size() <= self.max_size
+    }
+
+    pub fn records(&self) -> &[AccessRecord] {
+        &self.records
+    }
+}
+
+impl Default for AccessTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for AccessTrace {
+    fn name(&self) -> &str {
+        "Access Trace Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_access(&mut self, _block: &Block, _is_write: bool) {}
+    fn on_write(&mut self, _block: &Block) {}
+    fn on_read(&mut self, _block: &Block) {}
+
+    fn on_trace(&mut self, block: &Block, addr: *const u8, pc: usize, is_write: bool) {
+        if !self.is_selected(block) {
+            return;
+        }
+        let record = AccessRecord {
+            interval: self.interval,
+            block: block.ptr(),
+            addr,
+            pc,
+            is_write,
+        };
+        if self.records.push(record).is_err() {
+            self.dropped += 1;
+        }
+    }
+
+    fn on_interval(&mut self) {
+        info!("Access trace for interval #{}: {} accesses ({} dropped)", self.interval, self.records.len(), self.dropped);
+        for record in self.records.iter() {
+            info!(
+                "    {} {:?} in block {:?} from pc 0x{:x}",
+                if record.is_write { "W" } else { "R" },
+                record.addr,
+                record.block,
+                record.pc
+            );
+        }
+        self.records.clear();
+        self.dropped = 0;
+        self.interval += 1;
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{
+    site::{AllocationSite, SiteName},
+    track::Block,
+    MAX_TRACKED_ALLOCATIONS, READ_MANY_FAULTS,
+};
+use heapless::FnvIndexMap as IndexMap;
+use super::{IntervalTest, MAX_SURVEY_SITES};
+use tracing::*;
+
+/// How a block was used over its lifetime, judged from its access faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessPattern {
+    /// Never read or written: the allocation wasn't needed, or was too big
+    NeverTouched,
+    /// Written, then only read, at least `READ_MANY_FAULTS` times: a candidate
+    /// for read-only or shared memory
+    WriteOnceReadMany,
+    /// Written but never read back
+    WriteOnly,
+    /// Anything else, e.g. written again after being read
+    Mixed,
+}
+
+impl AccessPattern {
+    /// The patterns worth reporting.
+    pub const FLAGGED: [AccessPattern; 3] = [Self::NeverTouched, Self::WriteOnceReadMany, Self::WriteOnly];
+}
+
+/// What's known about one block's accesses so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct BlockUsage {
+    site: AllocationSite,
+    size: usize,
+    reads: usize,
+    writes: usize,
+    written_after_read: bool,
+}
+
+impl BlockUsage {
+    fn pattern(&self) -> AccessPattern {
+        match (self.reads, self.writes) {
+            (0, 0) => AccessPattern::NeverTouched,
+            (0, _) => AccessPattern::WriteOnly,
+            (reads, writes) if writes > 0 && !self.written_after_read && reads >= READ_MANY_FAULTS => AccessPattern::WriteOnceReadMany,
+            _ => AccessPattern::Mixed,
+        }
+    }
+}
+
+/// Blocks and bytes that fell into each `AccessPattern`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PatternBytes {
+    blocks: [usize; 4],
+    bytes: [usize; 4],
+}
+
+impl PatternBytes {
+    pub fn blocks(&self, pattern: AccessPattern) -> usize {
+        self.blocks[pattern as usize]
+    }
+
+    pub fn bytes(&self, pattern: AccessPattern) -> usize {
+        self.bytes[pattern as usize]
+    }
+
+    fn add(&mut self, usage: &BlockUsage) {
+        let pattern = usage.pattern();
+        self.blocks[pattern as usize] += 1;
+        self.bytes[pattern as usize] += usage.size;
+    }
+
+    fn is_flagged(&self) -> bool {
+        AccessPattern::FLAGGED.iter().any(|&pattern| self.blocks(pattern) > 0)
+    }
+}
+
+/// Finds blocks that were never touched, written once and then only read, or only
+/// ever written, and adds them up by allocation site.
+///
+/// Every block starts out protected, so its first access of each kind faults. A
+/// block freed without a single fault was never touched, and all its bytes were
+/// wasted; the bytes of a block never read back were wasted on writing it. A block
+/// whose writes all came before its first read, and that was read at least
+/// `READ_MANY_FAULTS` times, could have been read-only or shared.
+///
+/// Blocks are judged when they're freed. Live blocks are reported separately, by
+/// how they've been used so far.
+#[derive(Clone)]
+pub struct AccessPatternAnalysis {
+    interval: u64,
+    live: IndexMap<*const u8, BlockUsage, MAX_TRACKED_ALLOCATIONS>,
+    sites: IndexMap<AllocationSite, PatternBytes, MAX_SURVEY_SITES>,
+    total: PatternBytes,
+}
+
+impl AccessPatternAnalysis {
+    pub fn new() -> Self {
+        Self {
+            interval: 0,
+            live: IndexMap::new(),
+            sites: IndexMap::new(),
+            total: PatternBytes::default(),
+        }
+    }
+
+    /// The patterns of freed blocks, by allocation site.
+    pub fn sites(&self) -> &IndexMap<AllocationSite, PatternBytes, MAX_SURVEY_SITES> {
+        &self.sites
+    }
+
+    /// The patterns of all freed blocks.
+    pub fn total(&self) -> PatternBytes {
+        self.total
+    }
+
+    /// The patterns of the live blocks, by how they've been used so far.
+    pub fn live(&self) -> PatternBytes {
+        let mut live = PatternBytes::default();
+        for usage in self.live.values() {
+            live.add(usage);
+        }
+        live
+    }
+
+    fn free(&mut self, block: &Block) {
+        let Some(usage) = self.live.remove(&block.ptr()) else {
+            return;
+        };
+        self.total.add(&usage);
+        match self.sites.get_mut(&usage.site) {
+            Some(site) => site.add(&usage),
+            None => {
+                let mut site = PatternBytes::default();
+                site.add(&usage);
+                if self.sites.insert(usage.site, site).is_err() {
+                    warn!("Too many allocation sites to analyze, not recording {}", SiteName(usage.site));
+                }
+            }
+        }
+    }
+
+    fn log_patterns(label: impl core::fmt::Display, patterns: &PatternBytes) {
+        info!("        {}: {} blocks never touched ({} bytes wasted), {} write-once-read-many ({} bytes), {} write-only ({} bytes wasted)",
+            label,
+            patterns.blocks(AccessPattern::NeverTouched), patterns.bytes(AccessPattern::NeverTouched),
+            patterns.blocks(AccessPattern::WriteOnceReadMany), patterns.bytes(AccessPattern::WriteOnceReadMany),
+            patterns.blocks(AccessPattern::WriteOnly), patterns.bytes(AccessPattern::WriteOnly));
+    }
+
+    fn report(&self) {
+        info!("    Interval #{}: access patterns of freed blocks by allocation site:", self.interval);
+        for (&site, patterns) in self.sites.iter() {
+            if patterns.is_flagged() {
+                Self::log_patterns(SiteName(site), patterns);
+            }
+        }
+        Self::log_patterns("All freed blocks", &self.total);
+        Self::log_patterns(format_args!("{} live blocks so far", self.live.len()), &self.live());
+    }
+}
+
+impl Default for AccessPatternAnalysis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for AccessPatternAnalysis {
+    fn name(&self) -> &str {
+        "Access Pattern Analysis Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, alloc: &Block) {
+        let usage = BlockUsage {
+            site: alloc.site(),
+            size: alloc.size(),
+            ..Default::default()
+        };
+        if self.live.insert(alloc.ptr(), usage).is_err() {
+            warn!("Too many live blocks to analyze {:?}", alloc);
+        }
+    }
+
+    fn on_dealloc(&mut self, dealloc: &Block) {
+        self.free(dealloc);
+    }
+
+    fn on_fault(&mut self, block: &Block, _addr: *const u8, is_write: bool) {
+        let Some(usage) = self.live.get_mut(&block.ptr()) else {
+            return;
+        };
+        if is_write {
+            usage.writes += 1;
+            usage.written_after_read |= usage.reads > 0;
+        } else {
+            usage.reads += 1;
+        }
+    }
+
+    fn on_interval(&mut self) {
+        self.interval += 1;
+        self.report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_blocks_are_grouped_by_how_they_were_used() {
+        let blocks = [
+            Block::new(0x1000 as *mut u8, 4096).with_site(0x10),
+            Block::new(0x3000 as *mut u8, 8192).with_site(0x10),
+            Block::new(0x7000 as *mut u8, 4096).with_site(0x20),
+            Block::new(0x9000 as *mut u8, 4096).with_site(0x20),
+        ];
+        let mut analysis = AccessPatternAnalysis::new();
+        blocks.iter().for_each(|block| analysis.on_alloc(block));
+
+        analysis.on_fault(&blocks[1], blocks[1].ptr(), true);
+        for _ in 0..READ_MANY_FAULTS {
+            analysis.on_fault(&blocks[1], blocks[1].ptr(), false);
+        }
+        analysis.on_fault(&blocks[2], blocks[2].ptr(), true);
+        analysis.on_fault(&blocks[3], blocks[3].ptr(), false);
+        analysis.on_fault(&blocks[3], blocks[3].ptr(), true);
+        assert_eq!(analysis.live().blocks(AccessPattern::Mixed), 1);
+        blocks.iter().for_each(|block| analysis.on_dealloc(block));
+
+        let (first, second) = (analysis.sites()[&0x10], analysis.sites()[&0x20]);
+        assert_eq!(first.bytes(AccessPattern::NeverTouched), 4096);
+        assert_eq!(first.bytes(AccessPattern::WriteOnceReadMany), 8192);
+        assert_eq!(second.bytes(AccessPattern::WriteOnly), 4096);
+        assert_eq!(second.blocks(AccessPattern::Mixed), 1, "written after being read");
+        assert_eq!(analysis.live().blocks(AccessPattern::NeverTouched), 0);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{globals::get_tracked_allocations, page_size, track::Block};
+use heapless::{FnvIndexMap as IndexMap, HistoryBuffer};
+use std::time::Instant;
+use super::IntervalTest;
+use tracing::*;
+
+/// The most distinct pages counted per interval; touches beyond it are still
+/// counted, but can't be told apart from pages already seen.
+pub const MAX_WORKING_SET_PAGES: usize = 8192;
+
+/// How many intervals of the working-set-size curve are kept.
+pub const MAX_WORKING_SET_HISTORY: usize = 256;
+
+const READ: u8 = 0x1;
+const WRITE: u8 = 0x2;
+
+/// The working set of one interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkingSetSample {
+    pub interval: u64,
+    /// Milliseconds since the test started
+    pub ms: u64,
+    /// Distinct pages touched, by reads, writes or both
+    pub pages: usize,
+    /// Distinct pages read
+    pub read_pages: usize,
+    /// Distinct pages written
+    pub written_pages: usize,
+    /// Faults on pages that didn't fit in `MAX_WORKING_SET_PAGES`, so the
+    /// working set is at least this many pages bigger
+    pub overflowed_faults: usize,
+    /// Bytes of every tracked block at the end of the interval
+    pub tracked_bytes: usize,
+}
+
+impl WorkingSetSample {
+    pub fn bytes(&self) -> usize {
+        self.pages * page_size()
+    }
+
+    pub fn read_bytes(&self) -> usize {
+        self.read_pages * page_size()
+    }
+
+    pub fn written_bytes(&self) -> usize {
+        self.written_pages * page_size()
+    }
+
+    /// The fraction of tracked bytes in the working set.
+    pub fn fraction(&self) -> f64 {
+        self.bytes() as f64 / self.tracked_bytes.max(1) as f64
+    }
+}
+
+/// Estimates the working set size: how many distinct pages of tracked blocks are
+/// touched in each interval, split by reads and writes.
+///
+/// Every fault re-protects all blocks, so a page faults again each time it's
+/// touched after another page was; each page is counted once per interval. A read
+/// fault only unprotects the page for reading, so a page written after being read
+/// faults again and counts as both, unless `UNPROTECT_READ_WRITE_ON_FAULT` is set,
+/// in which case writes after a read are missed.
+///
+/// Each interval's sample is logged alongside the total tracked bytes, and the
+/// last `MAX_WORKING_SET_HISTORY` samples are kept as the working-set-size curve.
+#[derive(Clone)]
+pub struct WorkingSetSize {
+    interval: u64,
+    start: Instant,
+    /// The pages touched this interval, with how they were touched
+    pages: IndexMap<usize, u8, MAX_WORKING_SET_PAGES>,
+    overflowed_faults: usize,
+    history: HistoryBuffer<WorkingSetSample, MAX_WORKING_SET_HISTORY>,
+}
+
+impl WorkingSetSize {
+    pub fn new() -> Self {
+        Self {
+            interval: 0,
+            start: Instant::now(),
+            pages: IndexMap::new(),
+            overflowed_faults: 0,
+            history: HistoryBuffer::new(),
+        }
+    }
+
+    /// The working-set-size curve, oldest sample first.
+    pub fn history(&self) -> impl Iterator<Item = &WorkingSetSample> {
+        self.history.oldest_ordered()
+    }
+
+    pub fn peak(&self) -> Option<WorkingSetSample> {
+        self.history().max_by_key(|sample| sample.pages).copied()
+    }
+
+    fn touch(&mut self, addr: *const u8, is_write: bool) {
+        let page = Block::page_of(addr as *mut u8).ptr() as usize;
+        let access = if is_write { WRITE } else { READ };
+        match self.pages.get_mut(&page) {
+            Some(accesses) => *accesses |= access,
+            None => {
+                if self.pages.insert(page, access).is_err() {
+                    self.overflowed_faults += 1;
+                }
+            }
+        }
+    }
+
+    /// End the interval, returning its sample.
+    fn sample(&mut self, tracked_bytes: usize) -> WorkingSetSample {
+        self.interval += 1;
+        let sample = WorkingSetSample {
+            interval: self.interval,
+            ms: self.start.elapsed().as_millis() as u64,
+            pages: self.pages.len(),
+            read_pages: self.pages.values().filter(|&&accesses| accesses & READ != 0).count(),
+            written_pages: self.pages.values().filter(|&&accesses| accesses & WRITE != 0).count(),
+            overflowed_faults: self.overflowed_faults,
+            tracked_bytes,
+        };
+        self.pages.clear();
+        self.overflowed_faults = 0;
+        self.history.write(sample);
+        sample
+    }
+
+    fn report(&self, sample: &WorkingSetSample) {
+        info!("    Interval #{} at {} ms: working set {} bytes ({} pages, {:.1}% of {} tracked bytes), {} bytes read, {} bytes written",
+            sample.interval, sample.ms, sample.bytes(), sample.pages, 100.0 * sample.fraction(), sample.tracked_bytes,
+            sample.read_bytes(), sample.written_bytes());
+        if sample.overflowed_faults > 0 {
+            warn!("    {} faults on pages beyond the first {}, the working set is underestimated",
+                sample.overflowed_faults, MAX_WORKING_SET_PAGES);
+        }
+        if let Some(peak) = self.peak() {
+            let mean = self.history().map(|sample| sample.bytes()).sum::<usize>() / self.history.len().max(1);
+            info!("    Peak working set {} bytes at interval #{}, mean {} bytes over the last {} intervals",
+                peak.bytes(), peak.interval, mean, self.history.len());
+        }
+        let curve = self.history()
+            .map(|sample| format!("{}", sample.bytes() / 1024))
+            .collect::<std::vec::Vec<_>>()
+            .join(" ");
+        debug!("    Working set curve (KiB per interval): {}", curve);
+    }
+}
+
+impl Default for WorkingSetSize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntervalTest for WorkingSetSize {
+    fn name(&self) -> &str {
+        "Working Set Size Interval Test"
+    }
+
+    fn boxed(&self) -> Box<dyn IntervalTest> {
+        Box::new(self.clone())
+    }
+
+    fn on_alloc(&mut self, _alloc: &Block) {}
+
+    fn on_dealloc(&mut self, _dealloc: &Block) {}
+
+    fn on_fault(&mut self, _block: &Block, addr: *const u8, is_write: bool) {
+        self.touch(addr, is_write);
+    }
+
+    fn on_interval(&mut self) {
+        let tracked_bytes = get_tracked_allocations().iter().map(|block| block.size()).sum();
+        let sample = self.sample(tracked_bytes);
+        self.report(&sample);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_are_counted_once_per_interval_by_access() {
+        let page = page_size();
+        let mut wss = WorkingSetSize::new();
+        let base = 0x10000 * page;
+        wss.touch(base as *const u8, false);
+        wss.touch((base + 8) as *const u8, true);
+        wss.touch((base + page) as *const u8, false);
+        wss.touch((base + page + 1) as *const u8, false);
+
+        let sample = wss.sample(4 * page);
+        assert_eq!((sample.pages, sample.read_pages, sample.written_pages), (2, 2, 1));
+        assert_eq!(sample.fraction(), 0.5);
+
+        wss.touch((base + 2 * page) as *const u8, true);
+        let sample = wss.sample(4 * page);
+        assert_eq!((sample.pages, sample.read_pages, sample.written_pages), (1, 0, 1));
+        assert_eq!(wss.peak().map(|peak| peak.interval), Some(1));
+    }
+}
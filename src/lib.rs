@@ -5,6 +5,10 @@ pub mod logger;
 pub mod interval;
 pub mod config;
 pub mod compress;
+pub mod timer;
+pub mod site;
+pub mod worker;
+pub mod state;
 
 pub use config::*;
 
@@ -12,4 +16,11 @@ pub fn page_size() -> usize {
     unsafe {
         libc::sysconf(libc::_SC_PAGESIZE) as usize
     }
-}
\ No newline at end of file
+}
+
+/// The process's resident set size in bytes, from `/proc/self/statm`.
+pub fn resident_set_size() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(resident_pages * page_size())
+}
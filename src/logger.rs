@@ -1,12 +1,9 @@
 use tracing::{Event, Subscriber, Level};
-use tracing_core::span::{Attributes, Id, Record};
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::Layer;
 use core::fmt::{Debug, Write};
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::filter::LevelFilter;
-use std::io::Write as _;
 use libc::{write, STDOUT_FILENO};
 
 // No-allocation logger that writes directly to stdout
@@ -32,7 +29,7 @@ impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for NoAllocLogger {
             let _ = write!(writer, "{:?}", value);
         });
 
-        let _ = writeln!(writer, ""); // Newline after log
+        let _ = writeln!(writer); // Newline after log
     }
 }
 
@@ -51,6 +48,11 @@ impl Write for LowLevelWriter {
 
 static mut LOGGING_INITIALIZED: bool = false;
 
+/// Install the no-allocation logger as the global subscriber, once.
+///
+/// # Safety
+///
+/// Not thread-safe: must not race with another call.
 pub unsafe fn init_logging() {
     unsafe {
         if LOGGING_INITIALIZED {
@@ -71,6 +73,7 @@ pub unsafe fn init_logging() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting tracing default failed");
 }
+#[cfg(test)]
 mod tests {
     use super::*;
 
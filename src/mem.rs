@@ -1,8 +1,9 @@
 extern crate libc;
 use core::ffi::c_void;
-use libc::{size_t, SA_SIGINFO, siginfo_t, ucontext_t, SIGBUS, SIGSEGV, sigaction, sighandler_t};
+use libc::{size_t, SA_SIGINFO, siginfo_t, ucontext_t, SIGBUS, SIGSEGV, SIGTRAP, sigaction, sighandler_t};
 
-use crate::{UNPROTECT_READ_WRITE_ON_FAULT, INTERVAL_CONFIG, globals::*, interval::IntervalTest, logger::init_logging, track::{Block, Permissions}};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::{UNPROTECT_READ_WRITE_ON_FAULT, SINGLE_STEP_TRACING, INTERVAL_CONFIG, globals::*, interval::{stall_pending, IntervalTest}, logger::init_logging, timer::start_interval_timer, site::caller_site, state::{block_state, futex_wait, futex_wake_all, BlockState}, track::{Block, Permissions}};
 // Store original malloc and free function pointers
 static mut ORIGINAL_MALLOC: Option<extern "C" fn(size_t) -> *mut c_void> = None;
 static mut ORIGINAL_FREE: Option<extern "C" fn(*mut c_void)> = None;
@@ -13,21 +14,46 @@ pub fn align_up_to_page_size(size: usize, page_size: usize) -> usize {
     (size + page_size - 1) & !(page_size - 1)
 }
 
+/// Set by `retry_fault` while handling a fault
+static RETRY_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// Leave the page of the fault being handled protected, because its contents
+/// couldn't be restored yet, e.g. from the far tier. The access faults again.
+pub(crate) fn retry_fault() {
+    RETRY_FAULT.store(true, Ordering::Relaxed);
+}
+
+/// Whatever handled SIGTRAP before `sigtrap_handler`, for the traps that aren't ours
+#[cfg(target_arch = "x86_64")]
+static mut PREVIOUS_SIGTRAP_ACTION: sigaction = unsafe { core::mem::zeroed() };
+
+/// The x86-64 trap flag in `EFLAGS`
+#[cfg(target_arch = "x86_64")]
+const TRAP_FLAG: i64 = 0x100;
+
 pub fn align_down_to_page_size(size: usize, page_size: usize) -> usize {
     size & !(page_size - 1)
 }
 
 /// Signal handler for SIGSEGV/SIGBUS
-extern "C" fn sigsegv_handler(sig: i32, info: *mut siginfo_t, context: *mut c_void) {
+extern "C" fn sigsegv_handler(_sig: i32, info: *mut siginfo_t, context: *mut c_void) {
+    handle_fault(info, context);
+    // Emulated far memory latency is served out of the hook, so that only the
+    // faulting thread waits for it
+    stall_pending();
+}
+
+fn handle_fault(info: *mut siginfo_t, context: *mut c_void) {
     // tracing::error!("⚠️ Caught signal: {} (Segfault or Bus Error)", sig);
     tracing::trace!("Caught fault on protected memory");
-    if is_in_hook() {
+    // A fault while another thread is in a hook, e.g. compressing this very block,
+    // waits for it to finish; only a fault from inside our own hook is fatal
+    if !wait_for_hook() {
         tracing::error!("Already in hook, exiting signal handler");
         std::process::exit(1);
-    } else {
-        enter_hook();
-        get_interval_test_suite_mut().schedule(&INTERVAL_CONFIG);
     }
+    get_interval_test_suite_mut().schedule(&INTERVAL_CONFIG);
+    RETRY_FAULT.store(false, Ordering::Relaxed);
 
     #[cfg(target_arch = "x86_64")]
     let si_addr = unsafe { (*info).si_addr() as *const u8 };
@@ -47,15 +73,60 @@ extern "C" fn sigsegv_handler(sig: i32, info: *mut siginfo_t, context: *mut c_vo
 
         tracing::trace!("Is write?: {:?}", is_write);
         tracing::trace!("Faulting address: {:?}", si_addr);
-        match get_tracked_allocation(si_addr as *const u8) {
+        let permissions = if UNPROTECT_READ_WRITE_ON_FAULT || is_write {
+            Permissions::READ | Permissions::WRITE
+        } else {
+            Permissions::READ
+        };
+        match get_tracked_allocation(si_addr) {
             Some(allocation) => {
-                get_interval_test_suite_mut().on_access(&allocation, is_write);
+                #[cfg(target_arch = "x86_64")]
+                if !SINGLE_STEP_TRACING {
+                    let pc = unsafe { (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] } as usize;
+                    if let_straddled_access_through(&allocation, pc, si_addr, is_write, permissions) {
+                        exit_hook();
+                        return;
+                    }
+                }
+                get_interval_test_suite_mut().on_fault(&allocation, si_addr, is_write);
                 tracing::trace!("Faulting address is part of allocation: {:?}", allocation);
-                if UNPROTECT_READ_WRITE_ON_FAULT || is_write {
-                    Block::page_of(si_addr as *mut u8).change_permissions(Permissions::READ | Permissions::WRITE);
-                } else {
-                    Block::page_of(si_addr as *mut u8).change_permissions(Permissions::READ);
+                if RETRY_FAULT.swap(false, Ordering::Relaxed) {
+                    tracing::warn!("Leaving {:?} protected, the access will fault again", si_addr);
+                    exit_hook();
+                    return;
+                }
+
+                #[cfg(target_arch = "x86_64")]
+                if SINGLE_STEP_TRACING {
+                    let ucontext = context as *mut ucontext_t;
+                    let gregs = unsafe { &mut (*ucontext).uc_mcontext.gregs };
+                    let pc = gregs[libc::REG_RIP as usize] as usize;
+                    get_interval_test_suite_mut().on_trace(&allocation, si_addr, pc, is_write);
+
+                    // Let the instruction run once, then trap back into `sigtrap_handler`.
+                    // The instruction may fault again on another page before it completes,
+                    // and `on_fault` re-protects the whole block, so unprotect every pending page.
+                    let page = Block::page_of(si_addr as *mut u8);
+                    let mut pages = SINGLE_STEP_PAGES.get();
+                    if !pages.contains(&Some(page)) {
+                        match pages.iter_mut().find(|pending| pending.is_none()) {
+                            Some(pending) => *pending = Some(page),
+                            None => {
+                                tracing::error!("Too many pages pending single-step, leaving {:?} unprotected", page);
+                                page.unprotect();
+                            }
+                        }
+                    }
+                    for page in pages.iter().flatten() {
+                        page.unprotect();
+                    }
+                    SINGLE_STEP_PAGES.set(pages);
+                    gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+                    exit_hook();
+                    return;
                 }
+
+                Block::page_of(si_addr as *mut u8).change_permissions(permissions);
             },
             None => {
                 tracing::error!("Faulting address is not part of any tracked allocation");
@@ -67,68 +138,113 @@ extern "C" fn sigsegv_handler(sig: i32, info: *mut siginfo_t, context: *mut c_vo
     exit_hook();
 }
 
+/// Signal handler for SIGTRAP, raised after single-stepping a traced access
+#[cfg(target_arch = "x86_64")]
+extern "C" fn sigtrap_handler(sig: i32, info: *mut siginfo_t, context: *mut c_void) {
+    unsafe {
+        // Only this thread's pending step is ours to finish
+        let pages = SINGLE_STEP_PAGES.replace([None; 4]);
+        if pages.iter().all(Option::is_none) {
+            // Not one of ours, e.g. an `int3` or `raise(SIGTRAP)`
+            chain_sigtrap(sig, info, context);
+            return;
+        }
+        for page in pages.iter().flatten() {
+            page.protect();
+        }
+
+        if !context.is_null() {
+            let ucontext = context as *mut ucontext_t;
+            (*ucontext).uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+        }
+    }
+}
+
+/// Hand a SIGTRAP that isn't ours to the previous handler. With the default action,
+/// restore it and raise the signal again, so it's delivered once this handler returns.
+#[cfg(target_arch = "x86_64")]
+unsafe fn chain_sigtrap(sig: i32, info: *mut siginfo_t, context: *mut c_void) {
+    let previous = *core::ptr::addr_of!(PREVIOUS_SIGTRAP_ACTION);
+    match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => {
+            sigaction(SIGTRAP, &previous, core::ptr::null_mut());
+            libc::raise(SIGTRAP);
+        }
+        handler if previous.sa_flags & SA_SIGINFO != 0 => {
+            let handler = core::mem::transmute::<sighandler_t, extern "C" fn(i32, *mut siginfo_t, *mut c_void)>(handler);
+            handler(sig, info, context);
+        }
+        handler => {
+            let handler = core::mem::transmute::<sighandler_t, extern "C" fn(i32)>(handler);
+            handler(sig);
+        }
+    }
+}
+
 // Detect whether the faulting instruction was a read or a write
+#[cfg(target_arch = "aarch64")]
 unsafe fn detect_faulting_operation(ip: *const u8) -> Option<&'static str> {
     if ip.is_null() {
         tracing::error!("Instruction Pointer is null");
         return None;
     }
-    
-    #[cfg(target_arch = "x86_64")]
-    {
-        match *ip {
-            0x8B => Some("READ"),  // MOV opcode (reading from memory)
-            0x89 => Some("WRITE"), // MOV opcode (writing to memory)
-            _ => None,
-        }
-    }
 
-    #[cfg(target_arch = "aarch64")]
-    {
-        let instr = *(ip as *const u32); // Read 32-bit instruction
-        tracing::trace!("Instruction: {:#010x}", instr);
-
-        // Check for LOAD instructions (LDR, LDRB, LDRH, LDRSW)
-        if (instr & 0xFFC00000) == 0xB9400000 ||  // LDR (32-bit/64-bit)
-           (instr & 0xFFC00000) == 0x39400000 ||  // LDRB (Load Byte)
-           (instr & 0xFFC00000) == 0x79400000 {   // LDRH (Load Halfword)
-            return Some("READ");
-        }
-
-        // Check for STORE instructions (STR, STRB, STRH)
-        if (instr & 0xFFC00000) == 0xB9000000 ||  // STR (32-bit/64-bit)
-           (instr & 0xFFC00000) == 0x39000000 ||  // STRB (Store Byte)
-           (instr & 0xFFC00000) == 0x79000000 {   // STRH (Store Halfword)
-            return Some("WRITE");
-        }
+    let instr = *(ip as *const u32); // Read 32-bit instruction
+    tracing::trace!("Instruction: {:#010x}", instr);
 
+    // Check for LOAD instructions (LDR, LDRB, LDRH, LDRSW)
+    if (instr & 0xFFC00000) == 0xB9400000 ||  // LDR (32-bit/64-bit)
+       (instr & 0xFFC00000) == 0x39400000 ||  // LDRB (Load Byte)
+       (instr & 0xFFC00000) == 0x79400000 {   // LDRH (Load Halfword)
+        return Some("READ");
+    }
 
-        None
+    // Check for STORE instructions (STR, STRB, STRH)
+    if (instr & 0xFFC00000) == 0xB9000000 ||  // STR (32-bit/64-bit)
+       (instr & 0xFFC00000) == 0x39000000 ||  // STRB (Store Byte)
+       (instr & 0xFFC00000) == 0x79000000 {   // STRH (Store Halfword)
+        return Some("WRITE");
     }
+
+    None
 }
 
 /// Setup signal handler with SA_SIGINFO (for context capture)
 unsafe fn setup_signal_handler() {
     let mut sa: sigaction = std::mem::zeroed();
     sa.sa_flags = SA_SIGINFO;
-    sa.sa_sigaction = sigsegv_handler as sighandler_t;
+    sa.sa_sigaction = sigsegv_handler as extern "C" fn(i32, *mut siginfo_t, *mut c_void) as sighandler_t;
 
     sigaction(SIGSEGV, &sa, core::ptr::null_mut());
     sigaction(SIGBUS, &sa, core::ptr::null_mut());
+
+    #[cfg(target_arch = "x86_64")]
+    if SINGLE_STEP_TRACING {
+        let mut sa: sigaction = std::mem::zeroed();
+        sa.sa_flags = SA_SIGINFO;
+        sa.sa_sigaction = sigtrap_handler as extern "C" fn(i32, *mut siginfo_t, *mut c_void) as sighandler_t;
+        let mut previous: sigaction = std::mem::zeroed();
+        sigaction(SIGTRAP, &sa, &mut previous);
+        // This runs on every hook, so only the first call finds someone else's handler
+        if previous.sa_sigaction != sa.sa_sigaction {
+            PREVIOUS_SIGTRAP_ACTION = previous;
+        }
+    }
 }
 
 /// Initialize function pointers at load time
 unsafe fn init_hooks() {
     init_logging();
     setup_signal_handler();
-    if ORIGINAL_MALLOC.is_some() && ORIGINAL_FREE.is_some() {
+    if (*core::ptr::addr_of!(ORIGINAL_MALLOC)).is_some() && (*core::ptr::addr_of!(ORIGINAL_FREE)).is_some() {
         return;
     }
 
-    let malloc_sym = libc::dlsym(libc::RTLD_NEXT, b"malloc\0".as_ptr() as *const i8);
-    let free_sym = libc::dlsym(libc::RTLD_NEXT, b"free\0".as_ptr() as *const i8);
-    let mmap_sym = libc::dlsym(libc::RTLD_NEXT, b"mmap\0".as_ptr() as *const i8);
-    let munmap_sym = libc::dlsym(libc::RTLD_NEXT, b"munmap\0".as_ptr() as *const i8);
+    let malloc_sym = libc::dlsym(libc::RTLD_NEXT, c"malloc".as_ptr());
+    let free_sym = libc::dlsym(libc::RTLD_NEXT, c"free".as_ptr());
+    let mmap_sym = libc::dlsym(libc::RTLD_NEXT, c"mmap".as_ptr());
+    let munmap_sym = libc::dlsym(libc::RTLD_NEXT, c"munmap".as_ptr());
     if malloc_sym.is_null() {
         panic!("Failed to load malloc or free");
     }
@@ -142,10 +258,10 @@ unsafe fn init_hooks() {
         panic!("Failed to load munmap");
     }
 
-    ORIGINAL_MALLOC = Some(core::mem::transmute::<_, extern "C" fn(size_t) -> *mut c_void>(malloc_sym));
-    ORIGINAL_FREE = Some(core::mem::transmute::<_, extern "C" fn(*mut c_void)>(free_sym));
-    ORIGINAL_MMAP = Some(core::mem::transmute::<_, extern "C" fn(*mut c_void, size_t, i32, i32, i32, i32) -> *mut c_void>(mmap_sym));
-    ORIGINAL_MUNMAP = Some(core::mem::transmute::<_, extern "C" fn(*mut c_void, size_t) -> i32>(munmap_sym));
+    ORIGINAL_MALLOC = Some(core::mem::transmute::<*mut c_void, extern "C" fn(size_t) -> *mut c_void>(malloc_sym));
+    ORIGINAL_FREE = Some(core::mem::transmute::<*mut c_void, extern "C" fn(*mut c_void)>(free_sym));
+    ORIGINAL_MMAP = Some(core::mem::transmute::<*mut c_void, extern "C" fn(*mut c_void, size_t, i32, i32, i32, i32) -> *mut c_void>(mmap_sym));
+    ORIGINAL_MUNMAP = Some(core::mem::transmute::<*mut c_void, extern "C" fn(*mut c_void, size_t) -> i32>(munmap_sym));
 }
 
 fn original_malloc(mut size: size_t) -> *mut c_void {
@@ -153,7 +269,7 @@ fn original_malloc(mut size: size_t) -> *mut c_void {
         init_hooks();
         if let Some(original_malloc) = ORIGINAL_MALLOC {
             size = if crate::ALIGN_ALLOCATIONS_TO_PAGE_SIZE {
-                align_up_to_page_size(size as usize, crate::page_size())
+                align_up_to_page_size(size, crate::page_size())
             } else {
                 size
             };
@@ -175,7 +291,7 @@ fn original_free(ptr: *mut c_void) {
     }
 }
 
-fn original_mmap(addr: *mut c_void, length: size_t, prot: i32, flags: i32, fd: i32, offset: i32) -> *mut c_void {
+pub(crate) fn original_mmap(addr: *mut c_void, length: size_t, prot: i32, flags: i32, fd: i32, offset: i32) -> *mut c_void {
     unsafe {
         init_hooks();
         if let Some(original_mmap) = ORIGINAL_MMAP {
@@ -191,7 +307,7 @@ fn original_munmap(addr: *mut c_void, mut length: size_t) -> i32 {
         init_hooks();
         if let Some(original_munmap) = ORIGINAL_MUNMAP {
             length = if crate::ALIGN_ALLOCATIONS_TO_PAGE_SIZE {
-                align_up_to_page_size(length as usize, crate::page_size())
+                align_up_to_page_size(length, crate::page_size())
             } else {
                 length
             };
@@ -203,41 +319,161 @@ fn original_munmap(addr: *mut c_void, mut length: size_t) -> i32 {
 }
 
 
-static mut IN_HOOK: bool = false;
-fn enter_hook() {
-    // Mark a static variable denoting that we are in the hook
-    tracing::trace!("Entering hook");
-    unsafe {
-        IN_HOOK = true;
+/// The thread ID of the thread inside a hook, or 0. Faults and allocation hooks on other threads wait on it as a futex.
+///
+/// There is one hook for the whole process, not one per block: the tracked
+/// allocations and the interval tests are shared, and only the thread inside the
+/// hook may touch them. So a fault on any block waits out whatever another thread
+/// is doing in the hook, an interval included, and faults are handled one at a time.
+static HOOK_OWNER: AtomicU32 = AtomicU32::new(0);
+/// Threads waiting in `wait_for_hook`, so `exit_hook` only wakes anyone when it must
+static HOOK_WAITERS: AtomicU32 = AtomicU32::new(0);
+
+std::thread_local! {
+    /// Set on the profiler's own threads, whose allocations are never tracked
+    static PROFILER_THREAD: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+    /// This thread's ID, cached to save a syscall on every hook
+    static THREAD_ID: core::cell::Cell<u32> = const { core::cell::Cell::new(0) };
+    /// This thread's last two faults outside single-stepping, the latest last
+    static LAST_FAULTS: core::cell::Cell<[Fault; 2]> = const { core::cell::Cell::new([Fault::NONE; 2]) };
+    /// Pages unprotected for the instruction this thread is single-stepping. An
+    /// instruction can straddle a page boundary, so keep a few of them.
+    static SINGLE_STEP_PAGES: core::cell::Cell<[Option<Block>; 4]> = const { core::cell::Cell::new([None; 4]) };
+}
+
+/// A fault handled by `sigsegv_handler`, as remembered by `let_straddled_access_through`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fault {
+    pc: usize,
+    addr: usize,
+    /// How many intervals had run when it was handled
+    interval: u64,
+}
+
+impl Fault {
+    const NONE: Fault = Fault { pc: 0, addr: 0, interval: 0 };
+}
+
+/// If this fault repeats the one before last, and the last was from the same
+/// instruction on the next or previous page, the instruction's access straddles the
+/// two pages. Each fault re-protects every block before unprotecting its own page,
+/// so it would fault on them in turn forever; unprotect both and return `true`.
+///
+/// Instructions that merely go on to the next page, like `rep movs` or a loop
+/// striding a page at a time, never come back to where they faulted before last.
+///
+/// Both pages were already counted when the access first faulted on them, so the
+/// tests only see these faults again if an interval ran since, or either block was
+/// compressed meanwhile, and the pages need decompressing.
+#[cfg(target_arch = "x86_64")]
+fn let_straddled_access_through(block: &Block, pc: usize, addr: *const u8, is_write: bool, permissions: Permissions) -> bool {
+    let mut suite = get_interval_test_suite_mut();
+    let fault = Fault { pc, addr: addr as usize, interval: suite.intervals_executed() };
+    let Ok([first, last]) = LAST_FAULTS.try_with(|faults| faults.replace([faults.get()[1], fault])) else {
+        return false;
+    };
+    let page = Block::page_of(addr as *mut u8).ptr() as usize;
+    let last_page = Block::page_of(last.addr as *mut u8).ptr() as usize;
+    if first.pc != pc || first.addr != fault.addr || last.pc != pc || page.abs_diff(last_page) != crate::page_size() {
+        return false;
     }
+    let Some(neighbour) = get_tracked_allocation(last.addr as *const u8) else {
+        return false;
+    };
+
+    tracing::trace!("Access at {:?} straddles the page of 0x{:x}", addr, last.addr);
+    let settled = [block, &neighbour].iter().all(|block| block_state(block) == BlockState::Resident);
+    if first.interval != fault.interval || !settled {
+        suite.on_fault(block, addr, is_write);
+        suite.on_fault(&neighbour, last.addr as *const u8, is_write);
+        if RETRY_FAULT.swap(false, Ordering::Relaxed) {
+            return true;
+        }
+    }
+    Block::page_of(last.addr as *mut u8).change_permissions(permissions);
+    Block::page_of(addr as *mut u8).change_permissions(permissions);
+    true
 }
 
-fn is_in_hook() -> bool {
-    unsafe {
-        IN_HOOK
+fn current_thread_id() -> u32 {
+    THREAD_ID
+        .try_with(|id| {
+            if id.get() == 0 {
+                id.set(unsafe { libc::gettid() } as u32);
+            }
+            id.get()
+        })
+        .unwrap_or_else(|_| unsafe { libc::gettid() } as u32)
+}
+
+/// Take the hook for this thread, waiting for any other thread inside it.
+/// Returns `false` if this thread is already inside it, or is a profiler thread.
+pub(crate) fn wait_for_hook() -> bool {
+    if PROFILER_THREAD.try_with(|thread| thread.get()).unwrap_or(false) {
+        return false;
     }
+    acquire_hook()
 }
 
-fn exit_hook() {
-    // Mark a static variable denoting that we are no longer in the hook
+/// Take the hook for this thread, waiting for any other thread inside it, even if
+/// this is a profiler thread. Returns `false` if this thread is already inside it.
+pub(crate) fn acquire_hook() -> bool {
+    let thread = current_thread_id();
+    loop {
+        match HOOK_OWNER.compare_exchange(0, thread, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                tracing::trace!("Entering hook");
+                return true;
+            }
+            Err(owner) if owner == thread => return false,
+            Err(owner) => {
+                HOOK_WAITERS.fetch_add(1, Ordering::AcqRel);
+                futex_wait(&HOOK_OWNER, owner);
+                HOOK_WAITERS.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+/// Take the hook on the way into one of the allocation hooks. Returns `false` if
+/// the caller mustn't track the call, and should pass it straight through.
+pub(crate) fn enter_hook() -> bool {
+    // Wait for any other thread inside the hook, so this call is still tracked
+    wait_for_hook()
+}
+
+/// Mark the calling thread as one of the profiler's own, so the hooks pass its calls straight through.
+pub(crate) fn mark_profiler_thread() {
+    let _ = PROFILER_THREAD.try_with(|thread| thread.set(true));
+}
+
+/// Whether this thread is inside the hook, or is a profiler thread. Another thread
+/// inside the hook doesn't count: the allocation hooks wait for it in `enter_hook`.
+pub(crate) fn is_in_hook() -> bool {
+    HOOK_OWNER.load(Ordering::Acquire) == current_thread_id() || PROFILER_THREAD.try_with(|thread| thread.get()).unwrap_or(false)
+}
+
+pub(crate) fn exit_hook() {
     tracing::trace!("Exiting hook");
-    unsafe {
-        IN_HOOK = false;
+    HOOK_OWNER.store(0, Ordering::Release);
+    if HOOK_WAITERS.load(Ordering::Acquire) > 0 {
+        futex_wake_all(&HOOK_OWNER);
     }
 }
 
-#[no_mangle]
+// The hooks aren't exported from unit test builds, where they would hook the
+// test harness's own allocations
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn malloc(size: size_t) -> *mut c_void {
-    if is_in_hook() {
+    if is_in_hook() || !enter_hook() {
         return original_malloc(size);
-    } else {
-        enter_hook();
     }
+    start_interval_timer();
     // let size = align_up_to_page_size(size as usize, crate::page_size());
     tracing::trace!("Allocating {size} bytes", size = size);
     let ptr = original_malloc(size);
 
-    match track_allocation(ptr as *mut u8, size) {
+    match track_allocation(ptr as *mut u8, size, caller_site()) {
         Ok(true) => {
             tracing::warn!("Block {ptr:?} with size {size} tracked, already had previous entry");
         },
@@ -264,22 +500,20 @@ pub extern "C" fn malloc(size: size_t) -> *mut c_void {
     ptr
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn free(ptr: *mut c_void) {
-    if is_in_hook() {
+    if is_in_hook() || !enter_hook() {
         return original_free(ptr);
-    } else {
-        enter_hook();
     }
 
     // libc::printf(b"[HOOKED] free(%p)\n\0".as_ptr() as *const i8, ptr);
     match track_deallocation(ptr as *const u8) {
-        Ok(dealloc) => {
+        Some(dealloc) => {
             // tracing::info!("Deallocation {ptr:?} tracked, already had previous entry", ptr = ptr);
             get_interval_test_suite_mut().on_dealloc(&dealloc);
         },
-        Err(e) => {
-            tracing::error!("Failed to track deallocation {ptr:?}: {e:?}");
+        None => {
+            tracing::error!("Failed to track deallocation {ptr:?}: not tracked");
         }
     }
 
@@ -290,15 +524,14 @@ pub extern "C" fn free(ptr: *mut c_void) {
 }
 
 // Now override mmap and munmap to track memory mappings
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn mmap(addr: *mut c_void, length: size_t, prot: i32, flags: i32, fd: i32, offset: i32) -> *mut c_void {
-    if is_in_hook() {
+    if is_in_hook() || !enter_hook() {
         return original_mmap(addr, length, prot, flags, fd, offset);
-    } else {
-        enter_hook();
     }
+    start_interval_timer();
 
-    let size = align_up_to_page_size(length as usize, crate::page_size());
+    let size = align_up_to_page_size(length, crate::page_size());
     let ptr = original_mmap(addr, size, prot, flags, fd, offset);
     if ptr.is_null() {
         tracing::error!("Failed to map memory");
@@ -308,7 +541,7 @@ pub extern "C" fn mmap(addr: *mut c_void, length: size_t, prot: i32, flags: i32,
 
     tracing::trace!("Mapping {size} bytes at {ptr:?}", size = size, ptr = ptr);
 
-    match track_allocation(ptr as *mut u8, length as usize) {
+    match track_allocation(ptr as *mut u8, length, caller_site()) {
         Ok(true) => {
             tracing::warn!("Block {ptr:?} with size {length} tracked, already had previous entry");
         },
@@ -334,24 +567,22 @@ pub extern "C" fn mmap(addr: *mut c_void, length: size_t, prot: i32, flags: i32,
     ptr
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn munmap(addr: *mut c_void, length: size_t) -> i32 {
-    if is_in_hook() {
+    if is_in_hook() || !enter_hook() {
         return original_munmap(addr, length);
-    } else {
-        enter_hook();
     }
 
-    let size = align_up_to_page_size(length as usize, crate::page_size());
+    let size = align_up_to_page_size(length, crate::page_size());
     tracing::trace!("Unmapping {size} bytes at {ptr:?}", size = size, ptr = addr);
 
     match track_deallocation(addr as *const u8) {
-        Ok(dealloc) => {
+        Some(dealloc) => {
             // tracing::info!("Deallocation {ptr:?} tracked, already had previous entry", ptr = ptr);
             get_interval_test_suite_mut().on_dealloc(&dealloc);
         },
-        Err(e) => {
-            tracing::error!("Failed to track deallocation {addr:?}: {e:?}");
+        None => {
+            tracing::error!("Failed to track deallocation {addr:?}: not tracked");
         }
     }
 
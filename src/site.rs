@@ -0,0 +1,89 @@
+use core::ffi::{c_void, CStr};
+use core::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Where a block was allocated: the return address of the application's call to
+/// `malloc` or `mmap`, or 0 if it couldn't be found.
+pub type AllocationSite = usize;
+
+/// The most frames walked looking for a caller outside this library.
+const MAX_SITE_FRAMES: usize = 32;
+
+const URC_NO_REASON: i32 = 0;
+const URC_END_OF_STACK: i32 = 5;
+
+extern "C" {
+    fn _Unwind_Backtrace(trace: extern "C" fn(*mut c_void, *mut c_void) -> i32, arg: *mut c_void) -> i32;
+    fn _Unwind_GetIP(context: *mut c_void) -> usize;
+}
+
+struct SiteSearch {
+    library_base: *mut c_void,
+    frames: usize,
+    site: AllocationSite,
+}
+
+/// The base address of the object containing `addr`, or null.
+fn object_base(addr: usize) -> *mut c_void {
+    let mut info: libc::Dl_info = unsafe { core::mem::zeroed() };
+    if unsafe { libc::dladdr(addr as *const c_void, &mut info) } == 0 {
+        return core::ptr::null_mut();
+    }
+    info.dli_fbase
+}
+
+extern "C" fn find_site(context: *mut c_void, arg: *mut c_void) -> i32 {
+    let search = unsafe { &mut *(arg as *mut SiteSearch) };
+    let ip = unsafe { _Unwind_GetIP(context) };
+    search.frames += 1;
+    if ip == 0 || search.frames > MAX_SITE_FRAMES {
+        return URC_END_OF_STACK;
+    }
+    if object_base(ip) != search.library_base {
+        search.site = ip;
+        return URC_END_OF_STACK;
+    }
+    URC_NO_REASON
+}
+
+/// The allocation site of the hook this is called from: the first return address
+/// on the stack that lies outside this library.
+///
+/// Unwinds with the same unwinder Rust panics use, so it doesn't allocate, and
+/// stops at the first frame that isn't ours.
+pub fn caller_site() -> AllocationSite {
+    let mut search = SiteSearch {
+        library_base: object_base(caller_site as fn() -> AllocationSite as usize),
+        frames: 0,
+        site: 0,
+    };
+    unsafe {
+        _Unwind_Backtrace(find_site, &mut search as *mut SiteSearch as *mut c_void);
+    }
+    search.site
+}
+
+/// Formats an allocation site as `symbol+offset`, falling back to `object+offset`
+/// or the bare address when there's no symbol.
+pub struct SiteName(pub AllocationSite);
+
+impl Display for SiteName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.0 == 0 {
+            return write!(f, "<unknown>");
+        }
+        let mut info: libc::Dl_info = unsafe { core::mem::zeroed() };
+        if unsafe { libc::dladdr(self.0 as *const c_void, &mut info) } == 0 {
+            return write!(f, "0x{:x}", self.0);
+        }
+        if !info.dli_sname.is_null() {
+            let symbol = unsafe { CStr::from_ptr(info.dli_sname) };
+            write!(f, "{}+0x{:x}", symbol.to_string_lossy(), self.0 - info.dli_saddr as usize)
+        } else if !info.dli_fname.is_null() {
+            let object = unsafe { CStr::from_ptr(info.dli_fname) }.to_string_lossy();
+            let object = object.rsplit('/').next().unwrap_or_default();
+            write!(f, "{}+0x{:x}", object, self.0 - info.dli_fbase as usize)
+        } else {
+            write!(f, "0x{:x}", self.0)
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{track::Block, MAX_TRACKED_ALLOCATIONS};
+
+/// Where a block is in being compressed. Only the thread that moved a block into
+/// a busy state may move it out again; every other thread waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum BlockState {
+    /// Every page holds the block's own bytes
+    Resident = 0,
+    /// A thread is compressing frames of the block
+    Compressing = 1,
+    /// Some frames are compressed; their pages must not be read until decompressed
+    Compressed = 2,
+    /// A thread is decompressing frames of the block
+    Decompressing = 3,
+}
+
+impl BlockState {
+    fn from_u32(state: u32) -> Self {
+        match state {
+            1 => Self::Compressing,
+            2 => Self::Compressed,
+            3 => Self::Decompressing,
+            _ => Self::Resident,
+        }
+    }
+
+    /// The state a block settles in once it's no longer busy.
+    pub fn settled(compressed: bool) -> Self {
+        if compressed {
+            Self::Compressed
+        } else {
+            Self::Resident
+        }
+    }
+
+    /// Whether another thread is in the middle of changing the block's bytes.
+    pub fn is_busy(self) -> bool {
+        matches!(self, Self::Compressing | Self::Decompressing)
+    }
+}
+
+/// Wait until `word` no longer holds `expected`, or a spurious wakeup.
+pub(crate) fn futex_wait(word: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            core::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+/// Wake every thread waiting on `word`.
+pub(crate) fn futex_wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG, i32::MAX);
+    }
+}
+
+const EMPTY: usize = 0;
+/// A slot whose block was forgotten; lookups probe past it
+const TOMBSTONE: usize = 1;
+
+struct StateSlot {
+    ptr: AtomicUsize,
+    state: AtomicU32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: StateSlot = StateSlot {
+    ptr: AtomicUsize::new(EMPTY),
+    state: AtomicU32::new(BlockState::Resident as u32),
+};
+
+/// An open-addressed table of block states. The states are futex words, so they
+/// live at fixed addresses; lookups don't lock, and only adding or forgetting a
+/// block takes `SLOTS_LOCK`.
+static SLOTS: [StateSlot; MAX_TRACKED_ALLOCATIONS] = [EMPTY_SLOT; MAX_TRACKED_ALLOCATIONS];
+static SLOTS_LOCK: Mutex<()> = Mutex::new(());
+
+fn probe(ptr: usize) -> impl Iterator<Item = &'static StateSlot> {
+    let start = (ptr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) % MAX_TRACKED_ALLOCATIONS;
+    (0..MAX_TRACKED_ALLOCATIONS).map(move |i| &SLOTS[(start + i) % MAX_TRACKED_ALLOCATIONS])
+}
+
+fn find(ptr: usize) -> Option<&'static StateSlot> {
+    probe(ptr)
+        .take_while(|slot| slot.ptr.load(Ordering::Acquire) != EMPTY)
+        .find(|slot| slot.ptr.load(Ordering::Acquire) == ptr)
+}
+
+fn find_or_insert(block: &Block) -> Option<&'static StateSlot> {
+    let ptr = block.ptr() as usize;
+    if let Some(slot) = find(ptr) {
+        return Some(slot);
+    }
+    let _lock = SLOTS_LOCK.lock();
+    if let Some(slot) = find(ptr) {
+        return Some(slot);
+    }
+    let slot = probe(ptr).find(|slot| matches!(slot.ptr.load(Ordering::Acquire), EMPTY | TOMBSTONE));
+    match slot {
+        Some(slot) => {
+            slot.state.store(BlockState::Resident as u32, Ordering::Release);
+            slot.ptr.store(ptr, Ordering::Release);
+        }
+        None => tracing::warn!("Too many blocks to track the state of {:?}", block),
+    }
+    slot
+}
+
+/// The state of `block`. Blocks that were never compressed are `Resident`.
+pub fn block_state(block: &Block) -> BlockState {
+    find(block.ptr() as usize).map_or(BlockState::Resident, |slot| BlockState::from_u32(slot.state.load(Ordering::Acquire)))
+}
+
+/// Wait while another thread is compressing or decompressing `block`, returning
+/// the state it settles in.
+pub fn wait_while_busy(block: &Block) -> BlockState {
+    let Some(slot) = find(block.ptr() as usize) else {
+        return BlockState::Resident;
+    };
+    loop {
+        let state = slot.state.load(Ordering::Acquire);
+        if !BlockState::from_u32(state).is_busy() {
+            return BlockState::from_u32(state);
+        }
+        futex_wait(&slot.state, state);
+    }
+}
+
+/// Claim `block` for compressing or decompressing, moving it into the busy state
+/// `busy` once no other thread has it. Returns the state it was in before.
+pub fn begin(block: &Block, busy: BlockState) -> BlockState {
+    debug_assert!(busy.is_busy());
+    let Some(slot) = find_or_insert(block) else {
+        return BlockState::Resident;
+    };
+    loop {
+        let state = slot.state.load(Ordering::Acquire);
+        if BlockState::from_u32(state).is_busy() {
+            futex_wait(&slot.state, state);
+        } else if slot.state.compare_exchange(state, busy as u32, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            return BlockState::from_u32(state);
+        }
+    }
+}
+
+/// Release `block` from a busy state claimed with `begin`, waking any threads
+/// waiting for it.
+pub fn finish(block: &Block, settled: BlockState) {
+    debug_assert!(!settled.is_busy());
+    if let Some(slot) = find(block.ptr() as usize) {
+        slot.state.store(settled as u32, Ordering::Release);
+        futex_wake_all(&slot.state);
+    }
+}
+
+/// Stop tracking the state of `block`, once it's freed.
+pub fn forget(block: &Block) {
+    let _lock = SLOTS_LOCK.lock();
+    if let Some(slot) = find(block.ptr() as usize) {
+        slot.ptr.store(TOMBSTONE, Ordering::Release);
+        slot.state.store(BlockState::Resident as u32, Ordering::Release);
+        futex_wake_all(&slot.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn blocks_move_through_their_states() {
+        let block = Block::new(0x7100_0000 as *mut u8, 8192);
+        assert_eq!(block_state(&block), BlockState::Resident);
+
+        assert_eq!(begin(&block, BlockState::Compressing), BlockState::Resident);
+        assert_eq!(block_state(&block), BlockState::Compressing);
+        finish(&block, BlockState::Compressed);
+        assert_eq!(begin(&block, BlockState::Decompressing), BlockState::Compressed);
+        finish(&block, BlockState::Resident);
+        assert_eq!(wait_while_busy(&block), BlockState::Resident);
+
+        forget(&block);
+        assert_eq!(block_state(&block), BlockState::Resident);
+    }
+
+    #[test]
+    fn faulting_threads_wait_for_the_compressing_thread() {
+        let block = Block::new(0x7200_0000 as *mut u8, 4096);
+        begin(&block, BlockState::Compressing);
+
+        let waiter = thread::spawn(move || wait_while_busy(&block));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        finish(&block, BlockState::Compressed);
+        assert_eq!(waiter.join().unwrap(), BlockState::Compressed);
+        forget(&block);
+    }
+}
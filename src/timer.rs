@@ -0,0 +1,43 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::{INTERVAL_CONFIG, INTERVAL_TIMER_MS, globals::get_interval_test_suite_mut, mem::{acquire_hook, exit_hook, mark_profiler_thread}};
+
+static TIMER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Start the interval timer thread, if `INTERVAL_TIMER_MS` is set. Only the first call has any effect.
+///
+/// The schedule runs on a profiler thread of its own rather than in a signal
+/// handler, so an interval never runs on top of an application thread that was
+/// interrupted inside libc, and the application keeps its signals to itself. Call
+/// from inside a hook, so the thread's own allocations aren't tracked.
+pub fn start_interval_timer() {
+    if INTERVAL_TIMER_MS.is_none() || TIMER_STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let mut thread: libc::pthread_t = 0;
+    let ret = unsafe { libc::pthread_create(&mut thread, core::ptr::null(), timer_main, core::ptr::null_mut()) };
+    if ret != 0 {
+        tracing::error!("Could not start the interval timer: error {}", ret);
+        return;
+    }
+    unsafe {
+        libc::pthread_detach(thread);
+    }
+    tracing::info!("Started interval timer with a period of {}ms", INTERVAL_TIMER_MS.unwrap_or(0));
+}
+
+extern "C" fn timer_main(_arg: *mut c_void) -> *mut c_void {
+    mark_profiler_thread();
+    let period = Duration::from_millis(INTERVAL_TIMER_MS.unwrap_or(0));
+    loop {
+        std::thread::sleep(period);
+        // Waits for whichever thread is inside a hook; it may have run the schedule already
+        if acquire_hook() {
+            tracing::trace!("Interval timer fired");
+            get_interval_test_suite_mut().schedule(&INTERVAL_CONFIG);
+            exit_hook();
+        }
+    }
+}
@@ -1,14 +1,37 @@
 use core::ffi::c_void;
 use std::fmt::Debug;
 use heapless::FnvIndexMap as IndexMap;
-use heapless::FnvIndexSet as IndexSet;
 use libc::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
 use core::fmt::{Formatter, Result as FmtResult};
-use libc::{MAP_PRIVATE, MAP_ANONYMOUS, mmap};
-use crate::compress::CompressionAlgorithm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicI32, Ordering};
+use crate::compress::{Compressor, CorruptFrame, Frame, FrameIndex, PayloadError, COMPRESSED_POOL};
 use crate::page_size;
+use crate::site::AllocationSite;
 
-use super::mem::{align_up_to_page_size, align_down_to_page_size};
+use super::mem::{align_up_to_page_size, align_down_to_page_size, retry_fault};
+
+static PROC_SELF_MEM: AtomicI32 = AtomicI32::new(-1);
+
+/// A descriptor for `/proc/self/mem`, opened on first use.
+fn proc_self_mem() -> i32 {
+    let fd = PROC_SELF_MEM.load(Ordering::Acquire);
+    if fd >= 0 {
+        return fd;
+    }
+    let fd = unsafe { libc::open(c"/proc/self/mem".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 {
+        tracing::error!("Could not open /proc/self/mem");
+        return fd;
+    }
+    match PROC_SELF_MEM.compare_exchange(-1, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => fd,
+        Err(opened) => {
+            unsafe { libc::close(fd) };
+            opened
+        }
+    }
+}
 
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,6 +47,7 @@ bitflags::bitflags! {
 pub struct Block {
     ptr: *mut u8,
     size_in_bytes: usize,
+    site: AllocationSite,
 }
 
 impl Block {
@@ -31,9 +55,21 @@ impl Block {
         Self {
             ptr,
             size_in_bytes,
+            site: 0,
         }
     }
 
+    /// Record where the block was allocated.
+    pub fn with_site(mut self, site: AllocationSite) -> Self {
+        self.site = site;
+        self
+    }
+
+    /// The code address the block was allocated from, or 0 if unknown.
+    pub fn site(&self) -> AllocationSite {
+        self.site
+    }
+
     pub fn ptr(&self) -> *const u8 {
         self.ptr
     }
@@ -42,12 +78,224 @@ impl Block {
         self.ptr
     }
 
-    pub fn compress(&mut self, compression_algorithm: CompressionAlgorithm) -> Option<usize> {
-        compression_algorithm.compress_in_place(self)
+    /// An empty frame index for this block, with frames of `COMPRESSION_FRAME_PAGES` pages.
+    pub fn frame_index(&self, compressor: &'static dyn Compressor) -> FrameIndex {
+        let frame_size = crate::COMPRESSION_FRAME_PAGES * crate::page_size();
+        FrameIndex::new(compressor, self.ptr as usize, self.size_in_bytes, frame_size)
+    }
+
+    /// Compress the block in place as frames of `COMPRESSION_FRAME_PAGES` pages.
+    /// Returns `None` if no frame could be compressed.
+    pub fn compress(&mut self, compressor: &'static dyn Compressor) -> Option<FrameIndex> {
+        let mut index = self.frame_index(compressor);
+        if self.compress_frames(&mut index) > 0 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Compress the block's frames into the compressed pool and release its pages.
+    /// Returns `None` if no frame could be compressed.
+    pub fn compress_out_of_line(&mut self, compressor: &'static dyn Compressor) -> Option<FrameIndex> {
+        let mut index = self.frame_index(compressor);
+        if self.compress_frames_out_of_line(&mut index) > 0 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Compress the block's resident frames in place. Returns the number of frames compressed.
+    /// Works on a copy of one frame at a time, written back through `/proc/self/mem`,
+    /// so the block can stay protected meanwhile.
+    pub fn compress_frames(&mut self, index: &mut FrameIndex) -> usize {
+        let mut frame = std::vec::Vec::new();
+        let mut compressed = 0;
+        for i in 0..index.frames().len() {
+            if index.frames()[i] != Frame::Resident {
+                continue;
+            }
+            let range = index.frame_range(i, self.size_in_bytes);
+            frame.resize(range.len(), 0);
+            // The rest of the block stays resident; reading it directly would fault
+            if !self.peek(range.start, &mut frame) {
+                break;
+            }
+            if !index.compress_frame(i, &mut frame) {
+                continue;
+            }
+            compressed += 1;
+            if matches!(index.frames()[i], Frame::Compressed { .. }) {
+                self.write_back(range.start, &frame);
+            }
+        }
+        compressed
+    }
+
+    /// Compress the block's resident frames into the compressed pool. Returns the number of frames compressed.
+    pub fn compress_frames_out_of_line(&mut self, index: &mut FrameIndex) -> usize {
+        self.compress_frames_protected(index, || false)
+    }
+
+    /// Compress the block's resident frames into the compressed pool without
+    /// touching its pages except to release them, so it can stay protected from
+    /// other threads meanwhile. Checks `stop` before each frame, keeping the frames
+    /// compressed so far if it returns `true`. Returns the number of frames compressed.
+    pub fn compress_frames_protected(&mut self, index: &mut FrameIndex, mut stop: impl FnMut() -> bool) -> usize {
+        let mut frame = std::vec::Vec::new();
+        let mut compressed = 0;
+        for i in 0..index.frames().len() {
+            if index.frames()[i] != Frame::Resident {
+                continue;
+            }
+            if stop() {
+                break;
+            }
+            let range = index.frame_range(i, self.size_in_bytes);
+            frame.resize(range.len(), 0);
+            if !self.peek(range.start, &mut frame) {
+                break;
+            }
+            match index.stage_frame(i, &frame, &mut COMPRESSED_POOL.lock()) {
+                Some(true) => compressed += 1,
+                Some(false) => {}
+                None => break,
+            }
+        }
+        // Releasing pages doesn't read or write them, so it's fine while they're protected
+        index.release_staged(self.as_mut_bytes());
+        compressed
+    }
+
+    /// Recompress the block's compressed frames with `compressor`, a frame at a time
+    /// like `compress_frames`. Returns how many fewer bytes the block takes up, or
+    /// `None` if a frame was corrupt.
+    pub fn recompress(&mut self, index: &mut FrameIndex, compressor: &'static dyn Compressor) -> Option<isize> {
+        index.set_compressor(compressor);
+        let mut frame = std::vec::Vec::new();
+        let mut saved = 0;
+        for i in 0..index.frames().len() {
+            let old = index.frames()[i];
+            if !matches!(old, Frame::Compressed { .. } | Frame::Pooled { .. }) {
+                continue;
+            }
+            let range = index.frame_range(i, self.size_in_bytes);
+            frame.resize(range.len(), 0);
+            if matches!(old, Frame::Compressed { .. }) && !self.peek(range.start, &mut frame) {
+                self.unprotect();
+                frame.copy_from_slice(&self.as_bytes()[range.clone()]);
+                self.protect();
+            }
+            match index.recompress_frame(i, range.start, &mut frame, &mut COMPRESSED_POOL.lock()) {
+                Ok(frame_saved) => saved += frame_saved,
+                Err(corrupt) => return self.check_frames(Err(corrupt)).and(None),
+            }
+            if matches!(index.frames()[i], Frame::Compressed { .. } | Frame::Resident) {
+                self.write_back(range.start, &frame);
+            }
+        }
+        Some(saved)
+    }
+
+    /// Decompress the whole block in place.
+    pub fn decompress(&mut self, index: &mut FrameIndex) -> Option<()> {
+        self.decompress_frames(index, 0..index.frames().len())
+    }
+
+    /// Decompress only the frames covering the page containing `ptr`.
+    pub fn decompress_page(&mut self, index: &mut FrameIndex, ptr: *const u8) -> Option<()> {
+        let page = Self::page_of(ptr as *mut u8);
+        let start = (page.ptr as usize).max(self.ptr as usize) - self.ptr as usize;
+        let end = (page.ptr as usize + page.size_in_bytes).min(self.ptr as usize + self.size_in_bytes) - self.ptr as usize;
+        let frames = index.frames_covering(start..end);
+        self.decompress_frames(index, frames)
+    }
+
+    /// Decompress the given frames one at a time, writing each into place through
+    /// `/proc/self/mem`, so the block can stay protected until every byte is back.
+    fn decompress_frames(&mut self, index: &mut FrameIndex, frames: Range<usize>) -> Option<()> {
+        let mut frame = std::vec::Vec::new();
+        for i in frames {
+            if index.frames()[i] == Frame::Resident {
+                continue;
+            }
+            let range = index.frame_range(i, self.size_in_bytes);
+            frame.resize(range.len(), 0);
+            // Only a frame compressed in place has anything in its pages worth reading
+            if matches!(index.frames()[i], Frame::Compressed { .. }) && !self.peek(range.start, &mut frame) {
+                return None;
+            }
+            let result = index.open_frame(i, range.start, &mut frame, &COMPRESSED_POOL.lock());
+            self.check_frames(result)?;
+            // The frame only counts as resident once its bytes are back
+            if !self.poke(range.start, &frame) {
+                return None;
+            }
+            index.mark_resident(i, &mut COMPRESSED_POOL.lock());
+        }
+        Some(())
+    }
+
+    /// Write `buf` over the protected block's bytes at `offset` with `poke`, or if
+    /// that fails, directly with the block briefly unprotected.
+    fn write_back(&self, offset: usize, buf: &[u8]) {
+        if !self.poke(offset, buf) {
+            self.unprotect();
+            self.as_mut_bytes()[offset..offset + buf.len()].copy_from_slice(buf);
+            self.protect();
+        }
+    }
+
+    /// Read the block's bytes at `offset` into `buf` through `/proc/self/mem`, which
+    /// ignores the block's protection.
+    pub fn peek(&self, offset: usize, buf: &mut [u8]) -> bool {
+        let addr = self.ptr as usize + offset;
+        let read = unsafe { libc::pread(proc_self_mem(), buf.as_mut_ptr() as *mut c_void, buf.len(), addr as libc::off_t) };
+        if read != buf.len() as isize {
+            tracing::error!("Could not read {} bytes at 0x{:x} of block {:?}", buf.len(), addr, self);
+            return false;
+        }
+        true
+    }
+
+    /// Write `buf` over the block's bytes at `offset` through `/proc/self/mem`,
+    /// which ignores the block's protection.
+    pub fn poke(&self, offset: usize, buf: &[u8]) -> bool {
+        let addr = self.ptr as usize + offset;
+        let written = unsafe { libc::pwrite(proc_self_mem(), buf.as_ptr() as *const c_void, buf.len(), addr as libc::off_t) };
+        if written != buf.len() as isize {
+            tracing::error!("Could not write {} bytes at 0x{:x} of block {:?}", buf.len(), addr, self);
+            return false;
+        }
+        true
     }
 
-    pub fn decompress(&mut self, compression_algorithm: CompressionAlgorithm, compressed_size: usize) -> Option<usize> {
-        compression_algorithm.decompress_in_place(self, compressed_size)
+    /// Report a frame of this block that failed its checks, aborting if `ABORT_ON_CORRUPT_FRAME` is set.
+    /// A frame that only couldn't be read back from the far tier isn't corrupt: it
+    /// stays evicted, and the faulting access is retried.
+    fn check_frames(&self, result: Result<(), CorruptFrame>) -> Option<()> {
+        let corrupt = match result {
+            Ok(()) => return Some(()),
+            Err(corrupt) => corrupt,
+        };
+        if let PayloadError::Unreadable { .. } = corrupt.error {
+            tracing::error!(
+                "Compressed frame {} at {:p} of block {:?} is still evicted: {}",
+                corrupt.frame, self.ptr.wrapping_add(corrupt.offset), self, corrupt.error
+            );
+            retry_fault();
+            return None;
+        }
+        tracing::error!(
+            "Compressed frame {} at {:p} of block {:?} is corrupt: {}",
+            corrupt.frame, self.ptr.wrapping_add(corrupt.offset), self, corrupt.error
+        );
+        if crate::ABORT_ON_CORRUPT_FRAME {
+            tracing::error!("Aborting to avoid handing corrupted memory to the application");
+            std::process::abort();
+        }
+        None
     }
 
     pub fn with_size(mut self, size_in_bytes: usize) -> Self {
@@ -66,10 +314,7 @@ impl Block {
     pub fn page_of(ptr: *mut u8) -> Self {
         let page_size = crate::page_size();
         let start = align_down_to_page_size(ptr as usize, page_size);
-        Self {
-            ptr: start as *mut u8,
-            size_in_bytes: page_size,
-        }
+        Self::new(start as *mut u8, page_size)
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -78,6 +323,8 @@ impl Block {
         }
     }
 
+    // A `Block` is only a copyable handle to memory it doesn't own
+    #[allow(clippy::mut_from_ref)]
     pub fn as_mut_bytes(&self) -> &mut [u8] {
         unsafe {
             core::slice::from_raw_parts_mut(self.ptr, self.size_in_bytes)
@@ -153,15 +400,11 @@ impl<const N: usize> Track<N> {
         }
     }
 
-    pub fn remove_ptr(&mut self, value: *const u8) -> Result<Block, ()> {
-        if let Some(alloc) = self.allocations.remove(&(value as *const u8)) {
-            Ok(alloc)
-        } else {
-            Err(())
-        }
+    pub fn remove_ptr(&mut self, value: *const u8) -> Option<Block> {
+        self.allocations.remove(&value)
     }
 
-    pub fn remove(&mut self, value: Block) -> Result<Block, ()> {
+    pub fn remove(&mut self, value: Block) -> Option<Block> {
         self.remove_ptr(value.ptr as *const u8)
     }
 
@@ -187,7 +430,19 @@ impl<const N: usize> Track<N> {
         self.allocations.values()
     }
 
-    pub fn into_iter(self) -> impl Iterator<Item = Block> {
+}
+
+impl<const N: usize> Default for Track<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> IntoIterator for Track<N> {
+    type Item = Block;
+    type IntoIter = core::iter::Map<<IndexMap<*const u8, Block, N> as IntoIterator>::IntoIter, fn((*const u8, Block)) -> Block>;
+
+    fn into_iter(self) -> Self::IntoIter {
         self.allocations.into_iter().map(|(_, v)| v)
     }
 }
@@ -247,7 +502,7 @@ pub fn virtual_to_physical(addr: *const u8) -> Option<u64> {
         }
 
         let pfn = entry & ((1 << 55) - 1);
-        return Some((pfn * page_size as u64) + (addr as u64 % page_size as u64));
+        Some((pfn * page_size as u64) + (addr as u64 % page_size as u64))
     }
 
     #[cfg(not(target_os = "linux"))]
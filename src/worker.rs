@@ -0,0 +1,212 @@
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use heapless::FnvIndexMap as IndexMap;
+use spin::Mutex;
+use std::sync::{Condvar, Mutex as WakeMutex};
+
+use crate::{
+    compress::FrameIndex,
+    mem::mark_profiler_thread,
+    state::{self, BlockState},
+    track::Block,
+    MAX_TRACKED_ALLOCATIONS,
+};
+
+/// Where a block handed to the compression worker is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for the worker; taking it back cancels it
+    Queued,
+    /// The worker is compressing its frames; taking it back stops the worker after
+    /// the current frame and waits for it
+    Compressing,
+    /// Compressed, waiting to be taken back
+    Done,
+}
+
+struct Job {
+    block: Block,
+    /// Held by the worker while the job is `Compressing`
+    index: Option<FrameIndex>,
+    state: JobState,
+    stop: bool,
+    compressed: usize,
+}
+
+struct Jobs(IndexMap<*const u8, Job, MAX_TRACKED_ALLOCATIONS>);
+
+unsafe impl Send for Jobs {}
+
+static JOBS: Mutex<Jobs> = Mutex::new(Jobs(IndexMap::new()));
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static WAKE: WakeMutex<()> = WakeMutex::new(());
+static WAKE_WORKER: Condvar = Condvar::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+static SUBMITTED: AtomicUsize = AtomicUsize::new(0);
+static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+static CANCELLED: AtomicUsize = AtomicUsize::new(0);
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// What the compression worker has done so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub submitted: usize,
+    pub completed: usize,
+    /// Jobs taken back before the worker started on them
+    pub cancelled: usize,
+    /// Jobs taken back while the worker was compressing them
+    pub stopped: usize,
+}
+
+pub fn worker_stats() -> WorkerStats {
+    WorkerStats {
+        submitted: SUBMITTED.load(Ordering::Relaxed),
+        completed: COMPLETED.load(Ordering::Relaxed),
+        cancelled: CANCELLED.load(Ordering::Relaxed),
+        stopped: STOPPED.load(Ordering::Relaxed),
+    }
+}
+
+/// Start the compression worker thread, if it isn't running yet.
+///
+/// The thread is created with `pthread_create` rather than `std::thread`, so that it
+/// can mark itself as a profiler thread before it allocates anything.
+pub fn start_worker() -> bool {
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return true;
+    }
+    let mut thread: libc::pthread_t = 0;
+    let ret = unsafe { libc::pthread_create(&mut thread, core::ptr::null(), worker_main, core::ptr::null_mut()) };
+    if ret != 0 {
+        tracing::error!("Could not start the compression worker: error {}", ret);
+        STARTED.store(false, Ordering::Release);
+        return false;
+    }
+    unsafe {
+        libc::pthread_detach(thread);
+    }
+    tracing::info!("Started the compression worker");
+    true
+}
+
+extern "C" fn worker_main(_arg: *mut c_void) -> *mut c_void {
+    mark_profiler_thread();
+
+    loop {
+        if !compress_next() {
+            let guard = WAKE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            drop(WAKE_WORKER.wait_while(guard, |_| QUEUED.load(Ordering::Acquire) == 0));
+        }
+    }
+}
+
+/// Compress the first queued job. Returns `false` if there wasn't one.
+fn compress_next() -> bool {
+    let (mut block, mut index) = {
+        let mut jobs = JOBS.lock();
+        let Some(job) = jobs.0.values_mut().find(|job| job.state == JobState::Queued) else {
+            return false;
+        };
+        job.state = JobState::Compressing;
+        QUEUED.fetch_sub(1, Ordering::AcqRel);
+        (job.block, job.index.take().expect("Queued job without a frame index"))
+    };
+
+    // The block stays protected throughout, so other threads can't see it half-released
+    let ptr = block.ptr();
+    let stop = || JOBS.lock().0.get(&ptr).is_some_and(|job| job.stop);
+    let compressed = block.compress_frames_protected(&mut index, stop);
+    let settled = BlockState::settled(index.is_compressed());
+
+    let mut jobs = JOBS.lock();
+    let job = jobs.0.get_mut(&block.ptr()).expect("Compressing job was taken");
+    job.index = Some(index);
+    job.compressed = compressed;
+    job.state = JobState::Done;
+    state::finish(&block, settled);
+    COMPLETED.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Hand `block` to the worker to compress its resident frames into the compressed
+/// pool. The block is `Compressing` from now until the job is taken back, so it
+/// stays protected and other threads wait for it. Returns the index if the job
+/// couldn't be queued.
+pub fn submit(block: Block, index: FrameIndex) -> Result<(), FrameIndex> {
+    if !start_worker() {
+        return Err(index);
+    }
+    let job = Job {
+        block,
+        index: Some(index),
+        state: JobState::Queued,
+        stop: false,
+        compressed: 0,
+    };
+    let mut jobs = JOBS.lock();
+    if jobs.0.contains_key(&block.ptr()) {
+        return Err(job.index.unwrap());
+    }
+    if let Err((_, job)) = jobs.0.insert(block.ptr(), job) {
+        return Err(job.index.unwrap());
+    }
+    state::begin(&block, BlockState::Compressing);
+    QUEUED.fetch_add(1, Ordering::AcqRel);
+    drop(jobs);
+
+    SUBMITTED.fetch_add(1, Ordering::Relaxed);
+    let _guard = WAKE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    WAKE_WORKER.notify_one();
+    Ok(())
+}
+
+/// The state of `block`'s job, if it has one.
+pub fn job_state(block: &Block) -> Option<JobState> {
+    JOBS.lock().0.get(&block.ptr()).map(|job| job.state)
+}
+
+/// Take `block` back from the worker, with whatever frames it compressed. A queued
+/// job is cancelled; a running one is stopped after its current frame, and waited for.
+pub fn take_job(block: &Block) -> Option<FrameIndex> {
+    loop {
+        {
+            let mut jobs = JOBS.lock();
+            let job = jobs.0.get_mut(&block.ptr())?;
+            match job.state {
+                JobState::Queued | JobState::Done => {
+                    if job.state == JobState::Queued {
+                        QUEUED.fetch_sub(1, Ordering::AcqRel);
+                        CANCELLED.fetch_add(1, Ordering::Relaxed);
+                        let compressed = job.index.as_ref().is_some_and(|index| index.is_compressed());
+                        state::finish(block, BlockState::settled(compressed));
+                    }
+                    return jobs.0.remove(&block.ptr()).and_then(|job| job.index);
+                }
+                JobState::Compressing if !job.stop => {
+                    job.stop = true;
+                    STOPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                JobState::Compressing => {}
+            }
+        }
+        state::wait_while_busy(block);
+    }
+}
+
+/// Take back every finished job, passing `f` each block, its index, and how many
+/// frames the worker compressed.
+pub fn drain_finished(mut f: impl FnMut(Block, FrameIndex, usize)) {
+    let mut jobs = JOBS.lock();
+    let finished = jobs
+        .0
+        .iter()
+        .filter(|(_, job)| job.state == JobState::Done)
+        .map(|(ptr, _)| *ptr)
+        .collect::<heapless::Vec<*const u8, MAX_TRACKED_ALLOCATIONS>>();
+    for ptr in finished {
+        if let Some(Job { block, index: Some(index), compressed, .. }) = jobs.0.remove(&ptr) {
+            f(block, index, compressed);
+        }
+    }
+}